@@ -0,0 +1,42 @@
+//! Errors of the configuration lookups, for callers needing to tell them apart
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MachlistError {
+    /// The environment isn't defined in the given section (servers, resources, ..)
+    #[error("cannot find specified target environment in {section}")]
+    EnvNotFound { env: String, section: &'static str },
+    #[error("cannot find {0}")]
+    MachineNotFound(String),
+    #[error("cannot find resource {0}")]
+    ResourceNotFound(String),
+    /// The name is the prefix of several entries
+    #[error("{name} is ambiguous: {}", .candidates.join(", "))]
+    Ambiguous {
+        name: String,
+        candidates: Vec<String>,
+    },
+    /// The machine (or jump host) has no address to connect to
+    #[error("{}", missing_address(.machine, *.jump))]
+    MissingAddress { machine: String, jump: bool },
+    #[error("resource file {} doesn't exist, create it or give another one with -r", .0.display())]
+    ConfigNotFound(PathBuf),
+    #[error("Invalid resource file {}", .file.display())]
+    ConfigParse {
+        file: PathBuf,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+fn missing_address(machine: &str, jump: bool) -> String {
+    if jump {
+        format!("jump machine {} doesn't have an IP", machine)
+    } else {
+        format!("machine {} doesn't have IP or name", machine)
+    }
+}
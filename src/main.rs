@@ -1,371 +1,4584 @@
 use anyhow::{anyhow, bail, Context, Result};
-use clap::{App, Arg, SubCommand};
-use serde::Deserialize;
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
 
-#[derive(Clone, Debug, Deserialize)]
+mod error;
+mod pattern;
+mod tagexpr;
+mod totp;
+
+use error::MachlistError;
+use pattern::Pattern;
+use tagexpr::TagExpr;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct Resource {
     username: Option<String>,
+    /// Environment used when none is given with `-t` (`alpha` by default)
+    default_env: Option<String>,
+    webhook_url: Option<String>,
+    /// Directory of the known hosts files of the environments (e.g. `env:CI_KNOWN_HOSTS`),
+    /// instead of the ssh directory
+    known_hosts_dir: Option<String>,
+    /// ssh configuration file used instead of `~/.ssh/config` (ssh -F)
+    ssh_config: Option<String>,
+    /// Pattern that environment, machine and resource names must match
+    naming_policy: Option<String>,
+    /// Groups of machines, defined as tag expressions (e.g. `web && !canary`)
+    #[serde(default)]
+    group: HashMap<String, String>,
     server: HashMap<String, EnvironmentDef<ServerDef>>,
     resource: HashMap<String, EnvironmentDef<ResourceDef>>,
+    /// Named lists of resources of an environment, tunneled together
+    #[serde(default)]
+    resource_group: HashMap<String, EnvironmentDef<Vec<String>>>,
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(default)]
+    ssh_defaults: SshDefaults,
+    #[serde(default)]
+    environment: HashMap<String, EnvironmentSettings>,
+}
+
+/// Settings applying to all the machines of an environment
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentSettings {
+    /// ProxyCommand template used for machines without a jump, where
+    /// `{host}` and `{port}` are replaced by the machine's host and port
+    proxy_command: Option<String>,
+    /// Command bringing up the network (e.g. a VPN) needed to reach the environment
+    network_up: Option<String>,
+    /// Command tearing down the network after the last connection
+    network_down: Option<String>,
+    /// Username of the machines of the environment, instead of the top-level one
+    username: Option<String>,
+    /// Terminal background color used during shells in the environment
+    color: Option<String>,
+    /// Default `at` of the resources of the environment
+    at: Option<String>,
+    /// Default maximum simultaneous connections through each jump host
+    max_concurrent: Option<usize>,
+    /// Network namespace the connections are made from (Linux)
+    netns: Option<String>,
+    /// ssh configuration file of the environment, instead of the top-level one
+    ssh_config: Option<String>,
+}
+
+/// Default values for the command line flags, the command line taking precedence
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    verbose: Option<u64>,
+    compress: Option<bool>,
+    keepalive: Option<u32>,
+    known_hosts_mode: Option<String>,
+    /// Prefer the IPv6 (true) or IPv4 (false) address of dual-stack machines
+    prefer_ipv6: Option<bool>,
+}
+
+/// ssh options added to the connections unless given otherwise, `0` disabling one
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SshDefaults {
+    /// Seconds before giving up connecting (10 by default)
+    connect_timeout: Option<u32>,
+    /// Seconds between the keepalive messages (30 by default)
+    server_alive_interval: Option<u32>,
+    /// Unanswered keepalive messages before disconnecting (3 by default)
+    server_alive_count_max: Option<u32>,
+}
+
+impl SshDefaults {
+    /// `-o` options of the defaults, leaving out the disabled ones and the ones
+    /// given on the command line
+    pub fn args(&self, common: &CommonArgs) -> Vec<String> {
+        let defaults = [
+            ("ConnectTimeout", self.connect_timeout.unwrap_or(10), false),
+            (
+                "ServerAliveInterval",
+                self.server_alive_interval.unwrap_or(30),
+                common.keepalive.is_some(),
+            ),
+            (
+                "ServerAliveCountMax",
+                self.server_alive_count_max.unwrap_or(3),
+                false,
+            ),
+        ];
+        defaults
+            .iter()
+            .filter(|(key, value, given)| {
+                *value > 0 && !given && !has_ssh_option(&common.ssh_options, key)
+            })
+            .map(|(key, value, _)| format!("-o{}={}", key, value))
+            .collect()
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// Whether one of the `KEY=VALUE` ssh options sets the given key (case insensitive, like ssh)
+fn has_ssh_option(options: &[String], key: &str) -> bool {
+    options.iter().any(|option| {
+        option
+            .split_once('=')
+            .is_some_and(|(k, _)| k.eq_ignore_ascii_case(key))
+    })
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct EnvironmentDef<D>(HashMap<String, D>);
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct ServerDef {
     ip: Option<String>,
+    /// ssh port, when not the default one
+    port: Option<u16>,
+    /// Addresses of dual-stack machines, chosen over `ip` by the address family preference
+    ipv4: Option<String>,
+    ipv6: Option<String>,
     name: Option<String>,
-    jump: Option<String>,
+    jump: Option<Jump>,
     proxy: Option<bool>,
+    /// Operating system hint (linux, freebsd, macos, ..)
+    os: Option<String>,
+    /// String expected in the output of `uname -n` on the machine, checked by `--verify-identity`
+    connect_banner_check: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Ports to knock, in order, before connecting to the machine
+    #[serde(default)]
+    knock: Vec<u16>,
+    /// Delay between each knock in milliseconds
+    knock_delay: Option<u64>,
+    /// Base32 TOTP secret of the machine's 2FA (e.g. `env:WEB_TOTP_SECRET`)
+    otp_secret: Option<String>,
+    /// Program used for shells (ssh by default)
+    backend: Option<Backend>,
+    /// Maximum simultaneous connections through the machine, when used as a jump host
+    max_concurrent: Option<usize>,
+    /// tmux session attached to (or created) by shells with the ssh backend
+    tmux_session: Option<String>,
+    /// ssh private key used for the machine, instead of the `--identity` one
+    identity: Option<String>,
+    /// Username of the machine, instead of the environment's or top-level one
+    username: Option<String>,
+    /// ProxyCommand reaching the machine (e.g. AWS SSM), instead of a jump
+    proxy_command: Option<String>,
+    /// Forward the ssh agent to the machine (e.g. to reach internal git)
+    forward_agent: Option<bool>,
+    /// Compress the connections to the machine (e.g. far away), like `--compress`
+    compress: Option<bool>,
+    /// Machine being phased out, `true` or a message (e.g. "use web-new")
+    deprecated: Option<Deprecated>,
+}
+
+/// Jump host, or jump hosts chained in connection order
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Jump {
+    Host(String),
+    Chain(Vec<String>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Deprecated {
+    Flag(bool),
+    Message(String),
+}
+
+/// Program connecting to a machine for a shell
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    Ssh,
+    /// Eternal Terminal, surviving roaming and disconnections
+    Et,
+    /// mosh, for high latency links
+    Mosh,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct ResourceDef {
     server: String,
-    at: String,
+    at: Option<String>,
     port: u16,
+    /// Local port of the tunnel, when it can't be the same as `port`
+    local_port: Option<u16>,
+    /// command run by `tunnel --connect`, `{host}` and `{port}` being the local end of the tunnel
+    client: Option<String>,
 }
 
-fn home() -> PathBuf {
+fn home() -> Result<PathBuf> {
     // even though it's deprecated, it's still a relatively good/cheaper option,
     // at least better than just getting $HOME directly ..
     #[allow(deprecated)]
-    std::env::home_dir().expect("HOME directory")
+    std::env::home_dir().ok_or_else(|| anyhow!("cannot find the HOME directory"))
+}
+
+/// Expand a leading `~` of a path to the HOME directory
+fn expand_home(path: &str) -> Result<String> {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            Ok(format!("{}{}", home()?.display(), rest))
+        }
+        _ => Ok(path.to_string()),
+    }
 }
 
-fn ssh_dir() -> PathBuf {
-    let mut path = home();
+fn ssh_dir() -> Result<PathBuf> {
+    let mut path = home()?;
     path.push(".ssh");
-    path
+    Ok(path)
 }
 
-fn machlist_local() -> PathBuf {
-    let mut path = home();
-    path.push(".machlist/resources.toml");
-    path
+/// Known hosts file dedicated to an environment, in the directory given with
+/// `--known-hosts-dir` or `known_hosts_dir`, or else the ssh directory
+fn known_hosts_file(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+) -> Result<PathBuf> {
+    let dir = common
+        .known_hosts_dir
+        .as_ref()
+        .or(resources.known_hosts_dir.as_ref());
+    let mut path = match dir {
+        Some(dir) => PathBuf::from(expand_home(&env_value(dir)?)?),
+        None => ssh_dir()?,
+    };
+    path.push(format!("known_hosts_machlist_{}", target_env));
+    Ok(path)
 }
 
-fn user_host(user: Option<&str>, host: &str) -> String {
-    match user {
-        Some(u) => format!("{}@{}", u, host),
-        None => host.to_string(),
+/// ssh configuration file of an environment, or else the top-level one
+fn ssh_config_file(resources: &Resource, target_env: &str) -> Result<Option<String>> {
+    let config = resources
+        .get_env_settings(target_env)
+        .and_then(|settings| settings.ssh_config.as_ref())
+        .or(resources.ssh_config.as_ref());
+    let path = match config {
+        None => return Ok(None),
+        Some(config) => expand_home(&env_value(config)?)?,
+    };
+    if !Path::new(&path).is_file() {
+        bail!("ssh config file {} doesn't exist", path)
     }
+    Ok(Some(path))
 }
 
-/// Get the resources file
-///
-/// If specified (Some), then we only this file directly,
-/// but when unspecified (None), we look at a local file called ./machlist-resources.toml
-/// and then ~/.machlist/resources.toml
-fn parse_resources<P: AsRef<Path>>(file: P) -> Result<Resource> {
-    let file = file.as_ref();
-    let content = std::fs::read_to_string(file)
-        .with_context(|| format!("Failed to parse resource file {}", file.display()))?;
+fn machlist_local() -> Result<PathBuf> {
+    let mut path = home()?;
+    path.push(".machlist/resources.toml");
+    Ok(path)
+}
 
-    let values: Resource = toml::de::from_str(&content)?;
-    Ok(values)
+/// Resources file of the project, found in a `.machlist` directory of the
+/// current directory or one of its parents (like git finds `.git`)
+fn machlist_project() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(".machlist/resources.toml"))
+        .find(|path| path.is_file())
 }
 
-impl Resource {
-    pub fn get_target_env(&self, target_env: &str) -> Result<&EnvironmentDef<ServerDef>> {
-        self.server
-            .get(target_env)
-            .ok_or_else(|| anyhow!("cannot find specified target environment in servers"))
+/// Get the resources file
+///
+/// If specified (Some), then we only use this file directly,
+/// but when unspecified (None), we look at a local file called ./machlist-resources.toml,
+/// then the project's `.machlist/resources.toml`, `$XDG_CONFIG_HOME/machlist/resources.toml`
+/// (`~/.config` by default) and finally ~/.machlist/resources.toml
+fn resolve_resource_file(explicit: Option<&str>) -> Result<PathBuf> {
+    if let Some(file) = explicit {
+        return Ok(file.into());
     }
+    let mut candidates = vec![PathBuf::from("./machlist-resources.toml")];
+    candidates.extend(machlist_project());
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => home().ok().map(|home| home.join(".config")),
+    };
+    candidates.extend(config_home.map(|dir| dir.join("machlist/resources.toml")));
+    candidates.extend(machlist_local().ok());
 
-    pub fn get_target_env_resources(
-        &self,
-        target_env: &str,
-    ) -> Result<&EnvironmentDef<ResourceDef>> {
-        self.resource.get(target_env).ok_or(anyhow!(
-            "cannot find specified target environment in resources"
-        ))
+    match candidates.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.clone()),
+        None => {
+            let tried: Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+            bail!(
+                "no resources file given with -r, nor found in: {} (create one of them, or give the file with -r)",
+                tried.join(", ")
+            )
+        }
     }
+}
 
-    pub fn get_username(&self) -> Result<Option<String>> {
-        match &self.username {
-            None => Ok(None),
-            Some(u) => {
-                if let Some(env_name) = u.strip_prefix("env:") {
-                    Ok(Some(std::env::var(env_name).with_context(|| {
-                        format!("Cannot find environment variable {}", env_name)
-                    })?))
-                } else {
-                    Ok(Some(u.clone()))
-                }
-            }
+/// Resources file created by `config edit` when there's none
+const RESOURCES_SKELETON: &str = "[server.alpha]
+
+[resource]
+";
+
+/// Open the resources file in `$EDITOR` (or vi), offering to create
+/// `~/.machlist/resources.toml` when there's none
+fn config_edit(explicit: Option<&str>) -> Result<()> {
+    use std::io::{BufRead, Write};
+    let file = match explicit {
+        Some(file) => PathBuf::from(file),
+        None => resolve_resource_file(None).or_else(|_| machlist_local())?,
+    };
+    if !file.exists() {
+        print!("{} doesn't exist, create it? [y/N] ", file.display());
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("{} not created", file.display())
+        }
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("cannot create {}", dir.display()))?;
         }
+        std::fs::write(&file, RESOURCES_SKELETON)
+            .with_context(|| format!("cannot create {}", file.display()))?;
     }
+
+    // the editor can come with arguments (e.g. `code -w`)
+    let editor = std::env::var("EDITOR")
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let mut command = Command::new(words.next().unwrap());
+    command.args(words).arg(&file);
+    Err(anyhow::Error::new(command.exec()).context(format!("cannot run {}", editor)))
 }
 
-impl EnvironmentDef<ServerDef> {
-    pub fn get_machine(&self, machine_name: &str) -> Result<&ServerDef> {
-        self.0
-            .get(machine_name)
-            .ok_or_else(|| anyhow!("cannot find {}", machine_name))
-    }
+fn machlist_network_dir() -> Result<PathBuf> {
+    let mut path = home()?;
+    path.push(".machlist/network");
+    Ok(path)
+}
 
-    pub fn list_non_proxies(&self) -> impl Iterator<Item = (&String, &ServerDef)> {
-        self.0.iter().filter(|(_, v)| !v.proxy.unwrap_or(false))
-    }
+fn machlist_tunnels_dir() -> Result<PathBuf> {
+    let mut path = home()?;
+    path.push(".machlist/tunnels");
+    Ok(path)
 }
 
-impl EnvironmentDef<ResourceDef> {
-    pub fn get_resource(&self, resource_name: &str) -> Result<&ResourceDef> {
-        self.0
-            .get(resource_name)
-            .ok_or(anyhow!("cannot find resource {}", resource_name))
+/// Host as written in the `host:port` forms (-J, -L, scp paths), where IPv6
+/// addresses are bracketed
+fn bracket_ipv6(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
     }
 }
 
-pub struct Ssh {
-    args: Vec<String>,
-    dest: String,
+/// Host without the brackets an IPv6 address may be configured with
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
 }
 
-fn ssh_login(
-    user: Option<&str>,
-    resources: &Resource,
-    target_env: &str,
-    machine_name: &str,
-) -> Result<Ssh> {
-    let envdef = resources.get_target_env(target_env)?;
-    let machine_def = envdef.get_machine(machine_name)?;
+fn user_host(user: Option<&str>, host: &str) -> String {
+    match user {
+        Some(u) => format!("{}@{}", u, host),
+        None => host.to_string(),
+    }
+}
 
-    let mut args = Vec::new();
+/// Name of the local user, from `$USER` or the password database
+fn local_username() -> Result<String> {
+    static USERNAME: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+    let username = USERNAME.get_or_init(|| {
+        std::env::var("USER")
+            .ok()
+            .filter(|u| !u.is_empty())
+            .or_else(|| {
+                // SAFETY: the entry is copied out before any other call to getpwuid
+                unsafe {
+                    let pw = libc::getpwuid(libc::geteuid());
+                    if pw.is_null() {
+                        return None;
+                    }
+                    let name = std::ffi::CStr::from_ptr((*pw).pw_name);
+                    Some(name.to_string_lossy().into_owned())
+                }
+            })
+    });
+    username
+        .clone()
+        .ok_or_else(|| anyhow!("cannot find the local username"))
+}
 
-    // user known hosts files option
-    let mut path = ssh_dir();
-    path.push(format!("known_hosts_machlist_{}", target_env));
-    let hostfile = path.as_path().display().to_string();
+/// Value of a setting, taken from the environment variable `VAR` when it's `env:VAR`
+fn env_value(value: &str) -> Result<String> {
+    match value.strip_prefix("env:") {
+        Some(env_name) => std::env::var(env_name)
+            .with_context(|| format!("Cannot find environment variable {}", env_name)),
+        None => Ok(value.to_string()),
+    }
+}
 
-    let user_known_host_arg = format!("-oUserKnownHostsFile={}", hostfile);
+/// Set the variables of a `KEY=VALUE` file in the environment, the ones
+/// already set being kept
+///
+/// Blank lines and `#` comments are skipped, a line can start with `export`,
+/// and a value can be quoted with `"` or `'`.
+fn load_env_file(file: &Path) -> Result<()> {
+    let content = std::fs::read_to_string(file)
+        .with_context(|| format!("cannot read env file {}", file.display()))?;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((key, value))
+                if !key.trim().is_empty()
+                    && key
+                        .trim()
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                (key.trim(), value.trim())
+            }
+            _ => bail!(
+                "{}:{}: invalid line, expecting KEY=VALUE",
+                file.display(),
+                i + 1
+            ),
+        };
+        let value = match value.as_bytes() {
+            [q @ (b'"' | b'\''), .., last] if q == last && value.len() > 1 => {
+                &value[1..value.len() - 1]
+            }
+            _ => value,
+        };
+        if std::env::var_os(key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
 
-    args.push(user_known_host_arg);
+/// Expand the `${VAR}` environment variables of a string, `$${` being a literal `${`
+fn interpolate_str(s: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            // the escaping `$` is kept as the literal one
+            out.push_str(&rest[..start]);
+            out.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated variable in {}", s))?;
+        let name = &rest[start + 2..start + end];
+        let valid = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            bail!("invalid variable name {:?} in {}", name, s)
+        }
+        let value = std::env::var(name)
+            .with_context(|| format!("Cannot find environment variable {}", name))?;
+        out.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
 
-    // jump option
-    let jump = match &machine_def.jump {
-        None => None,
-        Some(jump_machine) => Some(envdef.get_machine(jump_machine)?),
-    };
+/// Expand the environment variables of all the strings of a configuration value
+fn interpolate(value: &mut toml::Value, path: &str) -> Result<()> {
+    match value {
+        toml::Value::String(s) => {
+            *s = interpolate_str(s).with_context(|| format!("in {}", path))?;
+        }
+        toml::Value::Array(values) => {
+            for (i, v) in values.iter_mut().enumerate() {
+                interpolate(v, &format!("{}[{}]", path, i))?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, v) in table.iter_mut() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                interpolate(v, &path)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
 
-    match jump {
-        None => (),
-        Some(def) => {
-            let ip = def.ip.clone().expect("jump proxy to have an ip");
-            let jump_str = user_host(user.as_deref(), &ip);
-            args.push("-J".to_string());
-            args.push(jump_str);
+fn parse_resources<P: AsRef<Path>>(file: P) -> Result<Resource, MachlistError> {
+    let file = file.as_ref();
+    let content = match std::fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(MachlistError::ConfigNotFound(file.to_path_buf()))
+        }
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context(format!("Cannot read resource file {}", file.display()))
+                .into())
         }
     };
-
-    let ssh_dest = if let Some(ip) = &machine_def.ip {
-        user_host(user.as_deref(), ip)
-    } else if let Some(name) = &machine_def.name {
-        user_host(user.as_deref(), name)
-    } else {
-        bail!("targetted machine doesn't have IP or name")
+    let invalid = |source: Box<dyn std::error::Error + Send + Sync>| MachlistError::ConfigParse {
+        file: file.to_path_buf(),
+        source,
     };
-    Ok(Ssh {
-        args,
-        dest: ssh_dest,
-    })
-}
 
-fn shell(common: &CommonArgs, target_env: &str, machine_name: &str) -> Result<()> {
-    let resources = parse_resources(&common.res_file)?;
-    let user = resources.get_username()?;
+    let values: Resource = toml::de::from_str(&content).map_err(|e| invalid(e.into()))?;
 
-    let ssh_opt = ssh_login(user.as_deref(), &resources, target_env, machine_name)?;
+    // the file is first checked as is, so that errors point to its lines
+    let mut value = toml::Value::try_from(&values).map_err(|e| invalid(e.into()))?;
+    interpolate(&mut value, "").map_err(|e| invalid(e.into()))?;
+    let mut values: Resource = value.try_into().map_err(|e| invalid(Box::new(e)))?;
 
-    println!(
-        "connecting target environment={} dest={}",
-        target_env, machine_name,
-    );
+    // resources without `at` inherit the one of their environment
+    for (env_name, defs) in values.resource.iter_mut() {
+        let default_at = values
+            .environment
+            .get(env_name)
+            .and_then(|settings| settings.at.as_ref());
+        if let Some(default_at) = default_at {
+            for def in defs.0.values_mut().filter(|def| def.at.is_none()) {
+                def.at = Some(default_at.clone());
+            }
+        }
+    }
+    Ok(values)
+}
 
-    let mut command = Command::new("ssh");
+impl Resource {
+    pub fn get_target_env(
+        &self,
+        target_env: &str,
+    ) -> Result<&EnvironmentDef<ServerDef>, MachlistError> {
+        self.server
+            .get(target_env)
+            .ok_or_else(|| MachlistError::EnvNotFound {
+                env: target_env.to_string(),
+                section: "servers",
+            })
+    }
 
-    if common.verbose > 0 {
-        command.arg("-v");
+    pub fn get_env_settings(&self, target_env: &str) -> Option<&EnvironmentSettings> {
+        self.environment.get(target_env)
     }
 
-    for a in ssh_opt.args.into_iter() {
-        command.arg(a);
+    /// Get the tag expression of a group, checking it only uses defined tags
+    pub fn get_group(&self, group: &str) -> Result<TagExpr> {
+        let def = self
+            .group
+            .get(group)
+            .ok_or_else(|| anyhow!("cannot find group {}", group))?;
+        let expr = TagExpr::parse(def).with_context(|| format!("invalid group {}", group))?;
+        for tag in expr.tags() {
+            let defined = self
+                .server
+                .values()
+                .flat_map(|envdef| envdef.0.values())
+                .any(|def| def.tags.iter().any(|t| t == tag));
+            if !defined {
+                bail!("group {} uses tag {} which no machine has", group, tag)
+            }
+        }
+        Ok(expr)
     }
-    command.arg(ssh_opt.dest);
-    command.exec();
-    Ok(())
-}
 
-fn copy_from(
-    common: &CommonArgs,
-    target_env: &str,
-    machine_name: &str,
-    copy_path: &str,
-) -> Result<()> {
-    let resources = parse_resources(&common.res_file)?;
-    let user = resources.get_username()?;
+    pub fn get_target_env_resources(
+        &self,
+        target_env: &str,
+    ) -> Result<&EnvironmentDef<ResourceDef>, MachlistError> {
+        self.resource
+            .get(target_env)
+            .ok_or_else(|| MachlistError::EnvNotFound {
+                env: target_env.to_string(),
+                section: "resources",
+            })
+    }
 
-    let ssh_opt = ssh_login(user.as_deref(), &resources, target_env, machine_name)?;
+    pub fn get_target_env_resource_groups(
+        &self,
+        target_env: &str,
+    ) -> Result<&EnvironmentDef<Vec<String>>, MachlistError> {
+        self.resource_group
+            .get(target_env)
+            .ok_or_else(|| MachlistError::EnvNotFound {
+                env: target_env.to_string(),
+                section: "resource groups",
+            })
+    }
 
-    println!(
-        "connecting target environment={} dest={}",
-        target_env, machine_name
-    );
+    /// Resources of a resource group, which all have to exist
+    pub fn resource_group_members(&self, target_env: &str, group: &str) -> Result<Vec<&str>> {
+        let members = self
+            .get_target_env_resource_groups(target_env)?
+            .find(group)?
+            .map(|(_, members, _)| members)
+            .ok_or_else(|| anyhow!("cannot find resource group {}", group))?;
+        let defs = self.get_target_env_resources(target_env)?;
+        for member in members {
+            if !defs.0.contains_key(member) {
+                bail!(
+                    "resource group {} references unknown resource {}",
+                    group,
+                    member
+                )
+            }
+        }
+        if members.is_empty() {
+            bail!("resource group {} is empty", group)
+        }
+        Ok(members.iter().map(|m| m.as_str()).collect())
+    }
 
-    let mut command = Command::new("scp");
+    /// Maximum simultaneous connections through the jump hosts of an environment
+    pub fn jump_limits(&self, target_env: &str) -> Result<HashMap<&str, usize>> {
+        let default = self
+            .get_env_settings(target_env)
+            .and_then(|settings| settings.max_concurrent);
+        let limits = self
+            .get_target_env(target_env)?
+            .0
+            .iter()
+            .filter_map(|(name, def)| {
+                def.max_concurrent
+                    .or(default)
+                    .map(|limit| (name.as_str(), limit))
+            })
+            .collect();
+        Ok(limits)
+    }
 
-    if common.verbose > 0 {
-        command.arg("-v");
+    /// Environment given on the command line, or else the configured default one
+    /// (`alpha` if none), which then has to exist
+    pub fn target_env<'a>(&'a self, given: Option<&'a str>) -> Result<&'a str> {
+        if let Some(target_env) = given {
+            return Ok(target_env);
+        }
+        let target_env = self.default_env.as_deref().unwrap_or(DEFAULT_ENV);
+        if !self.server.contains_key(target_env) && !self.resource.contains_key(target_env) {
+            bail!(
+                "no target environment given with -t, and the default one {} doesn't exist",
+                target_env
+            )
+        }
+        Ok(target_env)
     }
 
-    for a in ssh_opt.args.into_iter() {
-        command.arg(a);
+    /// Username to connect to a machine as: the one given on the command line,
+    /// or else the machine's, its environment's, and finally the top-level one
+    pub fn get_username(
+        &self,
+        given: Option<&str>,
+        target_env: &str,
+        machine_name: &str,
+    ) -> Result<Option<String>> {
+        if let Some(user) = given {
+            return Ok(Some(user.to_string()));
+        }
+        let machine_def = self.get_target_env(target_env)?.get_machine(machine_name)?;
+        self.resolve_username(given, target_env, machine_def.username.as_deref())
+    }
+
+    /// Username of a machine with the given `username`, or of an address not
+    /// described in the resources file (`None`)
+    pub fn resolve_username(
+        &self,
+        given: Option<&str>,
+        target_env: &str,
+        machine_username: Option<&str>,
+    ) -> Result<Option<String>> {
+        if let Some(user) = given {
+            return Ok(Some(user.to_string()));
+        }
+        let username = machine_username
+            .or_else(|| {
+                self.get_env_settings(target_env)
+                    .and_then(|settings| settings.username.as_deref())
+            })
+            .or(self.username.as_deref());
+        match username {
+            Some("self") => local_username().map(Some),
+            username => username.map(env_value).transpose(),
+        }
     }
-    let src = format!("{}:{}", ssh_opt.dest, copy_path);
-    command.arg(src);
-    command.arg("./");
-    command.exec();
-    Ok(())
 }
 
-fn copy_to(
-    common: &CommonArgs,
-    target_env: &str,
-    machine_name: &str,
-    copy_path: &str,
-) -> Result<()> {
-    let resources = parse_resources(&common.res_file)?;
-    let user = resources.get_username()?;
+/// How a name given on the command line matched an entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameMatch {
+    Exact,
+    Prefix,
+    Substring,
+}
 
-    let ssh_opt = ssh_login(user.as_deref(), &resources, target_env, machine_name)?;
+impl NameMatch {
+    pub fn name(self) -> &'static str {
+        match self {
+            NameMatch::Exact => "name",
+            NameMatch::Prefix => "prefix",
+            NameMatch::Substring => "substring",
+        }
+    }
+}
 
-    println!(
-        "connecting target environment={} dest={}",
-        target_env, machine_name,
-    );
+impl<D> EnvironmentDef<D> {
+    /// Find an entry by its name, or else by a prefix or, when no name starts
+    /// with it, a part of the name matching a single entry
+    pub fn find(&self, name: &str) -> Result<Option<(&String, &D, NameMatch)>, MachlistError> {
+        if let Some((k, v)) = self.0.get_key_value(name) {
+            return Ok(Some((k, v, NameMatch::Exact)));
+        }
+        let mut rule = NameMatch::Prefix;
+        let mut candidates: Vec<(&String, &D)> =
+            self.0.iter().filter(|(k, _)| k.starts_with(name)).collect();
+        if candidates.is_empty() {
+            rule = NameMatch::Substring;
+            candidates = self.0.iter().filter(|(k, _)| k.contains(name)).collect();
+        }
+        match candidates.len() {
+            0 => Ok(None),
+            1 => Ok(candidates.pop().map(|(k, v)| (k, v, rule))),
+            _ => {
+                let mut candidates: Vec<String> =
+                    candidates.iter().map(|(k, _)| k.to_string()).collect();
+                candidates.sort_unstable();
+                Err(MachlistError::Ambiguous {
+                    name: name.to_string(),
+                    candidates,
+                })
+            }
+        }
+    }
+}
 
-    let mut command = Command::new("scp");
+impl EnvironmentDef<ServerDef> {
+    pub fn get_machine(&self, machine_name: &str) -> Result<&ServerDef, MachlistError> {
+        self.get_machine_entry(machine_name).map(|(_, def)| def)
+    }
 
-    if common.verbose > 0 {
-        command.arg("-v");
+    /// Get the machine along with its full name
+    pub fn get_machine_entry(
+        &self,
+        machine_name: &str,
+    ) -> Result<(&String, &ServerDef), MachlistError> {
+        self.find(machine_name)?
+            .map(|(name, def, _)| (name, def))
+            .ok_or_else(|| MachlistError::MachineNotFound(machine_name.to_string()))
     }
 
-    for a in ssh_opt.args.into_iter() {
-        command.arg(a);
+    /// Jump machines used to reach the given machine, in connection order
+    pub fn jump_chain(&self, machine_name: &str) -> Result<Vec<&String>> {
+        let machine_def = self.get_machine(machine_name)?;
+        Ok(machine_def.jumps().iter().collect())
     }
-    let dst = format!("{}:", ssh_opt.dest);
-    command.arg(copy_path);
-    command.arg(dst);
-    command.exec();
-    Ok(())
-}
 
-fn tunnel(
-    common: &CommonArgs,
-    target_env: &str,
-    resource_name: &str,
-    local_port: Option<&str>,
-) -> Result<()> {
-    use std::str::FromStr;
-    let local_port = local_port.map(|x| u16::from_str(x).expect("local port is not valid port"));
+    pub fn list_non_proxies(&self) -> impl Iterator<Item = (&String, &ServerDef)> {
+        self.0.iter().filter(|(_, v)| !v.proxy.unwrap_or(false))
+    }
 
-    let resources = parse_resources(&common.res_file)?;
-    let user = resources.get_username()?;
+    pub fn list_proxies(&self) -> impl Iterator<Item = (&String, &ServerDef)> {
+        self.0.iter().filter(|(_, v)| v.proxy.unwrap_or(false))
+    }
+}
 
-    let defs = resources.get_target_env_resources(target_env)?;
-    let def = defs.get_resource(resource_name)?;
+impl ServerDef {
+    pub fn jumps(&self) -> &[String] {
+        match &self.jump {
+            None => &[],
+            Some(Jump::Host(host)) => std::slice::from_ref(host),
+            Some(Jump::Chain(hosts)) => hosts,
+        }
+    }
 
-    let machine_name = &def.server;
-    let local_port = local_port.unwrap_or(def.port);
+    /// Address of the machine, in order of the preferred family if any,
+    /// falling back to the other family, then to `ip`
+    pub fn address(&self, prefer: Option<AddressFamily>) -> Option<&String> {
+        let (v4, v6) = (self.ipv4.as_ref(), self.ipv6.as_ref());
+        match prefer {
+            None => self.ip.as_ref().or(v4).or(v6),
+            Some(AddressFamily::Inet) => v4.or(v6).or(self.ip.as_ref()),
+            Some(AddressFamily::Inet6) => v6.or(v4).or(self.ip.as_ref()),
+        }
+    }
 
-    let ssh_opt = ssh_login(user.as_deref(), &resources, target_env, machine_name)?;
+    /// Host to connect to: the address (see `address`), else the name, with
+    /// their `env:` values resolved
+    pub fn host(&self, prefer: Option<AddressFamily>) -> Result<Option<String>> {
+        match (self.address(prefer), &self.name) {
+            (Some(ip), _) => Ok(Some(unbracket(&env_value(ip)?).to_string())),
+            (None, Some(name)) => env_value(name).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
 
-    println!(
-        "tunneling to target environment={} resource={} at port {}",
-        resource_name, machine_name, local_port
-    );
+    pub fn is_deprecated(&self) -> bool {
+        !matches!(self.deprecated, None | Some(Deprecated::Flag(false)))
+    }
 
-    let mut command = Command::new("ssh");
+    /// Check if the machine declares the given operating system (case insensitive)
+    pub fn is_os(&self, os: &str) -> bool {
+        self.os
+            .as_ref()
+            .map(|o| o.eq_ignore_ascii_case(os))
+            .unwrap_or(false)
+    }
+}
 
-    if common.verbose > 0 {
-        command.arg("-v");
+impl ResourceDef {
+    /// Host the resource is at, from the machine's point of view
+    pub fn at(&self) -> Result<&str> {
+        self.at.as_deref().map(unbracket).ok_or_else(|| {
+            anyhow!("resource has no `at` and its environment doesn't define a default one")
+        })
     }
 
-    for a in ssh_opt.args.into_iter() {
-        command.arg(a);
+    /// Local port tunnels to the resource listen on by default
+    pub fn default_local_port(&self) -> u16 {
+        self.local_port.unwrap_or(self.port)
     }
 
-    command.arg("-N"); // do not execute a remote command
-    command.arg("-L");
+    /// Local forwarding specification of the resource (`-L` argument)
+    ///
+    /// IPv6 addresses are bracketed, as ssh expects them
+    pub fn forwarding(&self, local_port: u16) -> Result<String> {
+        Ok(format!(
+            "{}:{}:{}",
+            local_port,
+            bracket_ipv6(self.at()?),
+            self.port
+        ))
+    }
 
-    let arg_forwarding = format!("{}:{}:{}", local_port, def.at, def.port);
-    command.arg(arg_forwarding);
+    /// ssh -R argument binding the resource's port on its machine, forwarded to the local port
+    pub fn reverse_forwarding(&self, local_port: u16) -> String {
+        format!("{}:localhost:{}", self.port, local_port)
+    }
 
-    command.arg(ssh_opt.dest);
-    command.exec();
-    Ok(())
+    /// Check that the forwarding target can be reached with the given address family
+    pub fn check_family(&self, family: AddressFamily) -> Result<()> {
+        let at = self.at()?;
+        match (at.parse::<IpAddr>(), family) {
+            (Ok(IpAddr::V6(_)), AddressFamily::Inet)
+            | (Ok(IpAddr::V4(_)), AddressFamily::Inet6) => {
+                bail!("{} is not an {} address", at, family.name())
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
-fn list(common: &CommonArgs, target_env: &Option<&str>) -> Result<()> {
-    let resources = parse_resources(&common.res_file)?;
+impl EnvironmentDef<ResourceDef> {
+    pub fn get_resource(&self, resource_name: &str) -> Result<&ResourceDef, MachlistError> {
+        self.get_resource_entry(resource_name).map(|(_, def)| def)
+    }
+
+    /// Get the resource along with its full name
+    pub fn get_resource_entry(
+        &self,
+        resource_name: &str,
+    ) -> Result<(&String, &ResourceDef), MachlistError> {
+        self.find(resource_name)?
+            .map(|(name, def, _)| (name, def))
+            .ok_or_else(|| MachlistError::ResourceNotFound(resource_name.to_string()))
+    }
+}
+
+const DEFAULT_SSH_PORT: u16 = 22;
+const DEFAULT_ENV: &str = "alpha";
+
+/// Address family used by ssh
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    Inet,
+    Inet6,
+}
+
+impl AddressFamily {
+    pub fn name(self) -> &'static str {
+        match self {
+            AddressFamily::Inet => "IPv4",
+            AddressFamily::Inet6 => "IPv6",
+        }
+    }
+
+    pub fn ssh_arg(self) -> &'static str {
+        match self {
+            AddressFamily::Inet => "-4",
+            AddressFamily::Inet6 => "-6",
+        }
+    }
+}
+
+pub struct Ssh {
+    args: Vec<String>,
+    dest: String,
+    /// Steps taken to resolve the connection, for `--trace`
+    trace: Vec<String>,
+}
+
+impl Ssh {
+    /// Remote path argument of scp and rsync (`dest:path`), where IPv6 addresses are bracketed
+    pub fn remote_path(&self, path: &str) -> String {
+        let (user, host) = match self.dest.split_once('@') {
+            Some((user, host)) => (Some(user), host),
+            None => (None, self.dest.as_str()),
+        };
+        format!("{}:{}", user_host(user, &bracket_ipv6(host)), path)
+    }
+}
+
+fn ssh_login(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+) -> Result<Ssh, MachlistError> {
+    let envdef = resources.get_target_env(target_env)?;
+    let jumps = jump_chain(common, envdef, machine_name)?;
+    ssh_login_through(common, resources, target_env, machine_name, &jumps)
+}
+
+/// Jump machines used to reach a machine, the one given with `--jump`
+/// replacing the configured ones
+fn jump_chain<'a>(
+    common: &CommonArgs,
+    envdef: &'a EnvironmentDef<ServerDef>,
+    machine_name: &str,
+) -> Result<Vec<&'a String>> {
+    let jump = match &common.jump {
+        None => return envdef.jump_chain(machine_name),
+        Some(jump) => jump,
+    };
+    let (full_name, _) = envdef.get_machine_entry(machine_name)?;
+    let (jump_name, _) = envdef
+        .get_machine_entry(jump)
+        .with_context(|| format!("invalid --jump {}", jump))?;
+    if jump_name == full_name {
+        bail!(
+            "invalid --jump {}, {} can't be reached through itself",
+            jump,
+            full_name
+        )
+    }
+    Ok(vec![jump_name])
+}
+
+/// Same as `ssh_login`, going through the given jump hosts instead of the machine's
+fn ssh_login_through(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+    jumps: &[&String],
+) -> Result<Ssh, MachlistError> {
+    let prefer = common.prefer_family;
+    let envdef = resources.get_target_env(target_env)?;
+    let (full_name, machine_def, rule) = envdef
+        .find(machine_name)?
+        .ok_or_else(|| MachlistError::MachineNotFound(machine_name.to_string()))?;
+    let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+
+    // ssh keeps the first value of an option, so the ones given on the
+    // command line come first to take precedence over the ones set here
+    let mut args: Vec<String> = common
+        .ssh_options
+        .iter()
+        .map(|option| format!("-o{}", option))
+        .collect();
+    let mut trace = Vec::new();
+    if rule == NameMatch::Exact {
+        trace.push(format!(
+            "machine {} found in environment {}",
+            full_name, target_env
+        ));
+    } else {
+        trace.push(format!(
+            "machine {} found in environment {} by {} {}",
+            full_name,
+            target_env,
+            rule.name(),
+            machine_name
+        ));
+    }
+
+    // user known hosts files option
+    let hostfile = known_hosts_file(common, resources, target_env)?
+        .display()
+        .to_string();
+
+    let user_known_host_arg = format!("-oUserKnownHostsFile={}", hostfile);
+
+    args.push(user_known_host_arg);
+
+    // ssh configuration file, the options set here overriding its ones
+    if let Some(config) = ssh_config_file(resources, target_env)? {
+        trace.push(format!("ssh config file {}", config));
+        args.push("-F".to_string());
+        args.push(config);
+    }
+
+    // identity file, the machine's one overriding the command line's
+    if let Some(identity) = machine_def.identity.as_ref().or(common.identity.as_ref()) {
+        let identity = expand_home(identity)?;
+        trace.push(format!("identity file {}", identity));
+        args.push("-i".to_string());
+        args.push(identity);
+    }
+
+    // compression of the machine, when not already enabled for all of them
+    if machine_def.compress.unwrap_or(false) && !common.compress {
+        trace.push("compression from the machine".to_string());
+        args.push("-C".to_string());
+    }
+
+    // agent forwarding only applies to the destination, not the jump hosts
+    if machine_def.forward_agent.unwrap_or(false) {
+        trace.push("agent forwarded".to_string());
+        args.push("-A".to_string());
+    }
+
+    // jump option, the hops being comma separated
+    let mut hops = Vec::new();
+    for jump_machine in jumps {
+        let def = envdef
+            .0
+            .get(jump_machine.as_str())
+            .ok_or_else(|| anyhow!("jump machine {} doesn't exist", jump_machine))?;
+        let ip = def
+            .address(prefer)
+            .ok_or_else(|| MachlistError::MissingAddress {
+                machine: jump_machine.to_string(),
+                jump: true,
+            })?;
+        let ip = env_value(ip)?;
+        let user = resources.get_username(common.user.as_deref(), target_env, jump_machine)?;
+        // unlike the destination, -J takes a host:port, so IPv6 addresses are bracketed
+        let mut hop = user_host(user.as_deref(), &bracket_ipv6(&ip));
+        if let Some(port) = def.port {
+            hop.push_str(&format!(":{}", port));
+        }
+        trace.push(format!("jump through {}", hop));
+        hops.push(hop);
+    }
+    if !hops.is_empty() {
+        args.push("-J".to_string());
+        args.push(hops.join(","));
+    }
+
+    let host = if let Some(ip) = machine_def.address(prefer) {
+        let ip = unbracket(&env_value(ip)?).to_string();
+        trace.push(format!("host {} from the machine's ip", ip));
+        ip
+    } else if let Some(name) = &machine_def.name {
+        let name = env_value(name)?;
+        trace.push(format!("host {} from the machine's name", name));
+        name
+    } else {
+        return Err(MachlistError::MissingAddress {
+            machine: full_name.to_string(),
+            jump: false,
+        });
+    };
+    let port = machine_def.port.unwrap_or(DEFAULT_SSH_PORT);
+    if let Some(port) = machine_def.port {
+        trace.push(format!("port {} from the machine", port));
+        args.push("-p".to_string());
+        args.push(port.to_string());
+    }
+
+    // proxy command of the machine, or else of the environment for machines
+    // not using a jump (ssh expanding %h and %p itself)
+    if let Some(template) = &machine_def.proxy_command {
+        if !jumps.is_empty() {
+            return Err(
+                anyhow!("machine {} has both a jump and a proxy_command", full_name).into(),
+            );
+        }
+        let proxy_command = env_value(template)?
+            .replace("{host}", &host)
+            .replace("{port}", &port.to_string());
+        trace.push(format!("proxy command {} from the machine", proxy_command));
+        args.push(format!("-oProxyCommand={}", proxy_command));
+    } else if jumps.is_empty() {
+        let proxy_command = resources
+            .get_env_settings(target_env)
+            .and_then(|settings| settings.proxy_command.as_ref());
+        if let Some(template) = proxy_command {
+            let proxy_command = template
+                .replace("{host}", &host)
+                .replace("{port}", &port.to_string());
+            trace.push(format!(
+                "proxy command {} from the environment",
+                proxy_command
+            ));
+            args.push(format!("-oProxyCommand={}", proxy_command));
+        }
+    }
+
+    args.extend(resources.ssh_defaults.args(common));
+
+    let ssh_dest = user_host(user.as_deref(), &host);
+    Ok(Ssh {
+        args,
+        dest: ssh_dest,
+        trace,
+    })
+}
+
+/// Split a `host[:port]` address, IPv6 addresses needing brackets to have a port
+fn parse_host_port(address: &str) -> Result<(&str, Option<u16>)> {
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) if host.starts_with('[') && host.ends_with(']') => {
+            (unbracket(host), Some(port))
+        }
+        Some((host, port)) if !host.contains(':') => (host, Some(port)),
+        _ => (unbracket(address), None),
+    };
+    if host.is_empty() {
+        bail!("invalid address {}, expecting host[:port]", address)
+    }
+    let port = port
+        .map(|port| port.parse::<u16>())
+        .transpose()
+        .with_context(|| format!("invalid port in {}", address))?;
+    Ok((host, port))
+}
+
+/// Connection to an address not described in the resources file, the
+/// environment only giving the known hosts file and the username
+fn ssh_login_adhoc(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    address: &str,
+) -> Result<Ssh> {
+    if common.jump.is_some() {
+        bail!("--jump can't be used with an address not in the resources file")
+    }
+    let (host, port) = parse_host_port(address)?;
+    let user = resources.resolve_username(common.user.as_deref(), target_env, None)?;
+
+    let mut args: Vec<String> = common
+        .ssh_options
+        .iter()
+        .map(|option| format!("-o{}", option))
+        .collect();
+    let mut trace = vec![format!("ad-hoc host {}", host)];
+
+    let hostfile = known_hosts_file(common, resources, target_env)?;
+    args.push(format!("-oUserKnownHostsFile={}", hostfile.display()));
+    if let Some(config) = ssh_config_file(resources, target_env)? {
+        trace.push(format!("ssh config file {}", config));
+        args.push("-F".to_string());
+        args.push(config);
+    }
+    if let Some(identity) = &common.identity {
+        let identity = expand_home(identity)?;
+        trace.push(format!("identity file {}", identity));
+        args.push("-i".to_string());
+        args.push(identity);
+    }
+    if let Some(port) = port {
+        trace.push(format!("port {} from the address", port));
+        args.push("-p".to_string());
+        args.push(port.to_string());
+    }
+    args.extend(resources.ssh_defaults.args(common));
+
+    Ok(Ssh {
+        args,
+        dest: user_host(user.as_deref(), host),
+        trace,
+    })
+}
+
+/// Record a connection about to be made in the `--log` and `--trace` files
+fn record_connection(
+    common: &CommonArgs,
+    subcommand: &str,
+    target_env: &str,
+    machine_name: &str,
+    ssh_opt: &Ssh,
+) -> Result<()> {
+    write_log(common, subcommand, target_env, machine_name, ssh_opt)?;
+    write_trace(common, subcommand, target_env, machine_name, ssh_opt)
+}
+
+/// Append a line to the `--log` file, written before ssh replaces the process
+fn write_log(
+    common: &CommonArgs,
+    subcommand: &str,
+    target_env: &str,
+    machine_name: &str,
+    ssh_opt: &Ssh,
+) -> Result<()> {
+    use std::io::Write;
+    let log_file = match &common.log {
+        Some(log_file) if !common.dry_run => log_file,
+        _ => return Ok(()),
+    };
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let line = format!(
+        "{} subcommand={} env={} machine={} dest={}\n",
+        timestamp, subcommand, target_env, machine_name, ssh_opt.dest
+    );
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .and_then(|mut file| file.write_all(line.as_bytes()).map(|_| file))
+        .with_context(|| format!("cannot write log file {}", log_file.display()))?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Resolution of a connection, as written in the trace file
+#[derive(Serialize)]
+struct TraceJson<'a> {
+    config_file: String,
+    subcommand: &'a str,
+    env: &'a str,
+    machine: &'a str,
+    steps: &'a [String],
+    dest: &'a str,
+    args: &'a [String],
+}
+
+/// Write the resolution of a connection as JSON in the trace file, if any
+fn write_trace(
+    common: &CommonArgs,
+    subcommand: &str,
+    target_env: &str,
+    machine_name: &str,
+    ssh_opt: &Ssh,
+) -> Result<()> {
+    let trace_file = match &common.trace {
+        None => return Ok(()),
+        Some(trace_file) => trace_file,
+    };
+    let trace = TraceJson {
+        config_file: common.res_file.display().to_string(),
+        subcommand,
+        env: target_env,
+        machine: machine_name,
+        steps: &ssh_opt.trace,
+        dest: &ssh_opt.dest,
+        args: &ssh_opt.args,
+    };
+    let content = format!("{}\n", serde_json::to_string(&trace)?);
+    std::fs::write(trace_file, content)
+        .with_context(|| format!("cannot write trace file {}", trace_file.display()))
+}
+
+/// Print how a connection was resolved, for humans, instead of connecting
+fn explain(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+    jumps: &[&String],
+    ssh_opt: &Ssh,
+    command: &Command,
+) -> Result<()> {
+    println!("environment: {}", target_env);
+    println!("machine: {}", machine_name);
+    let jump_hosts: Vec<&str> = ssh_opt
+        .args
+        .iter()
+        .skip_while(|arg| *arg != "-J")
+        .nth(1)
+        .map(|hops| hops.split(',').collect())
+        .unwrap_or_default();
+    if jump_hosts.is_empty() {
+        println!("jump hosts: none");
+    } else {
+        println!("jump hosts:");
+        for (name, host) in jumps.iter().zip(jump_hosts) {
+            println!("  {}: {}", name, host);
+        }
+    }
+    println!("destination: {}", ssh_opt.dest);
+    println!(
+        "known hosts file: {}",
+        known_hosts_file(common, resources, target_env)?.display()
+    );
+    println!("resolution:");
+    for step in ssh_opt.trace.iter() {
+        println!("  {}", step);
+    }
+    println!("command: {}", command_line(command));
+    Ok(())
+}
+
+/// Shared bring-up of an environment network
+///
+/// Connections to the same environment share the network brought up by the
+/// first one, keeping a count of the connections in progress, and the last
+/// connection to finish tears it down.
+struct NetworkGuard {
+    target_env: String,
+    down: String,
+}
+
+fn network_run_hook(hook: &str, target_env: &str, cmd: &str) -> Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .status()
+        .with_context(|| format!("cannot run {} of {}", hook, target_env))?;
+    if !status.success() {
+        bail!("{} of {} failed with {}", hook, target_env, status)
+    }
+    Ok(())
+}
+
+/// Update the number of connections in progress for the environment, returning the previous count
+fn network_refcount(target_env: &str, delta: i64) -> Result<i64> {
+    let dir = machlist_network_dir()?;
+    std::fs::create_dir_all(&dir)?;
+    let count_file = dir.join(format!("{}.count", target_env));
+    let lock_file = dir.join(format!("{}.lock", target_env));
+
+    let mut attempts = 0;
+    while std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_file)
+        .is_err()
+    {
+        attempts += 1;
+        if attempts > 200 {
+            bail!("cannot lock {}", lock_file.display())
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let count = std::fs::read_to_string(&count_file)
+        .ok()
+        .and_then(|c| c.trim().parse::<i64>().ok())
+        .unwrap_or(0);
+    let written = std::fs::write(&count_file, (count + delta).max(0).to_string());
+    std::fs::remove_file(&lock_file)?;
+    written?;
+    Ok(count)
+}
+
+/// Bring up the network of the environment, if it defines one
+///
+/// Without a `network_down` there's nothing to tear down, so `network_up`
+/// is run before each connection and is expected to be idempotent.
+fn network_up(resources: &Resource, target_env: &str) -> Result<Option<NetworkGuard>> {
+    let settings = match resources.get_env_settings(target_env) {
+        None => return Ok(None),
+        Some(settings) => settings,
+    };
+    match (&settings.network_up, &settings.network_down) {
+        (None, None) => Ok(None),
+        (Some(up), None) => {
+            network_run_hook("network_up", target_env, up)?;
+            Ok(None)
+        }
+        (up, Some(down)) => {
+            if network_refcount(target_env, 1)? == 0 {
+                if let Some(up) = up {
+                    if let Err(e) = network_run_hook("network_up", target_env, up) {
+                        network_refcount(target_env, -1)?;
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(Some(NetworkGuard {
+                target_env: target_env.to_string(),
+                down: down.clone(),
+            }))
+        }
+    }
+}
+
+impl Drop for NetworkGuard {
+    fn drop(&mut self) {
+        match network_refcount(&self.target_env, -1) {
+            Ok(1) => {
+                if let Err(e) = network_run_hook("network_down", &self.target_env, &self.down) {
+                    eprintln!("{:#}", e)
+                }
+            }
+            Ok(_) => (),
+            Err(e) => eprintln!("{:#}", e),
+        }
+    }
+}
+
+/// Bring up the network of the environment and knock on the entry machine,
+/// unless the command is only printed
+fn before_connect(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+) -> Result<Option<NetworkGuard>> {
+    if common.dry_run {
+        return Ok(None);
+    }
+    let network = network_up(resources, target_env)?;
+    knock(common, resources, target_env, machine_name)?;
+    Ok(network)
+}
+
+/// Knock the ports of the machine through which the connection enters
+///
+/// The entry is the first jump host, or the machine itself when not using a
+/// jump, since only this one is reachable from here. Each knock is a TCP
+/// connection attempt with a short timeout, whose result doesn't matter.
+fn knock(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+) -> Result<()> {
+    use std::net::TcpStream;
+    const KNOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(300);
+    const DEFAULT_KNOCK_DELAY: u64 = 200;
+
+    let envdef = resources.get_target_env(target_env)?;
+    let entry = match jump_chain(common, envdef, machine_name)?.first() {
+        Some(jump) => envdef.get_machine(jump)?,
+        None => envdef.get_machine(machine_name)?,
+    };
+    if entry.knock.is_empty() {
+        return Ok(());
+    }
+    let host = entry
+        .host(None)?
+        .ok_or_else(|| anyhow!("machine to knock doesn't have IP or name"))?;
+    let delay = std::time::Duration::from_millis(entry.knock_delay.unwrap_or(DEFAULT_KNOCK_DELAY));
+
+    for port in entry.knock.iter() {
+        let addr = (host.as_str(), *port)
+            .to_socket_addrs()
+            .with_context(|| format!("cannot resolve {} to knock", host))?
+            .next()
+            .ok_or_else(|| anyhow!("cannot resolve {} to knock", host))?;
+        let _ = TcpStream::connect_timeout(&addr, KNOCK_TIMEOUT);
+        std::thread::sleep(delay);
+    }
+    Ok(())
+}
+
+/// Terminal background color, reset when dropped
+struct TerminalColor;
+
+impl TerminalColor {
+    /// Set the background color (OSC 11) of the terminal, if stdout is one
+    pub fn set(color: &str) -> Option<Self> {
+        use std::io::Write;
+        let mut stdout = std::io::stdout();
+        if !stdout.is_terminal() {
+            return None;
+        }
+        let _ = write!(stdout, "\x1b]11;{}\x07", color);
+        let _ = stdout.flush();
+        Some(TerminalColor)
+    }
+}
+
+impl Drop for TerminalColor {
+    fn drop(&mut self) {
+        use std::io::Write;
+        let mut stdout = std::io::stdout();
+        let _ = write!(stdout, "\x1b]111\x07");
+        let _ = stdout.flush();
+    }
+}
+
+const HOSTS_FILE: &str = "/etc/hosts";
+
+/// Entry of the hosts file, removed when dropped
+struct HostsEntry {
+    line: String,
+}
+
+impl HostsEntry {
+    /// Add an entry resolving the name to the local host
+    ///
+    /// This needs to be able to write the hosts file, so usually root privileges
+    pub fn add_local(name: &str) -> Result<Self> {
+        use std::io::Write;
+        let line = format!("127.0.0.1 {} # machlist tunnel", name);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(HOSTS_FILE)
+            .and_then(|mut f| writeln!(f, "{}", line).map(|_| f))
+            .with_context(|| {
+                format!(
+                    "cannot add {} to {} (need root privileges?)",
+                    name, HOSTS_FILE
+                )
+            })?;
+        file.flush()?;
+        Ok(HostsEntry { line })
+    }
+}
+
+impl Drop for HostsEntry {
+    fn drop(&mut self) {
+        let result = std::fs::read_to_string(HOSTS_FILE).and_then(|content| {
+            let kept: Vec<&str> = content.lines().filter(|l| *l != self.line).collect();
+            let mut content = kept.join("\n");
+            content.push('\n');
+            std::fs::write(HOSTS_FILE, content)
+        });
+        if let Err(e) = result {
+            eprintln!("cannot remove `{}` from {}: {}", self.line, HOSTS_FILE, e)
+        }
+    }
+}
+
+/// File written for the duration of the connection, removed when dropped
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn write(path: PathBuf, content: &str) -> Result<Self> {
+        std::fs::write(&path, content)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        Ok(TempFile { path })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// What needs to be undone once the connection terminates
+#[derive(Default)]
+struct Teardown {
+    network: Option<NetworkGuard>,
+    color: Option<TerminalColor>,
+    hosts: Vec<HostsEntry>,
+    file: Option<TempFile>,
+}
+
+impl Teardown {
+    pub fn is_empty(&self) -> bool {
+        self.network.is_none()
+            && self.color.is_none()
+            && self.hosts.is_empty()
+            && self.file.is_none()
+    }
+}
+
+/// Spawn a command in the foreground
+///
+/// The terminal interruptions are left to the command, the current process
+/// ignoring them so that it can tear things down once the command terminates.
+fn spawn_foreground(command: &mut Command) -> std::io::Result<Child> {
+    // SAFETY: only async-signal-safe calls are made between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+    }
+    Ok(child)
+}
+
+/// Run the connection command
+///
+/// The command replaces the current process, unless something needs to be
+/// torn down after it, in which case we wait for it and exit with its status.
+fn run(common: &CommonArgs, mut command: Command, teardown: Teardown) -> Result<()> {
+    if common.dry_run {
+        println!("{}", command_line(&command));
+        return Ok(());
+    }
+    if teardown.is_empty() {
+        return Err(command.exec().into());
+    }
+    let start = std::time::Instant::now();
+    let status = spawn_foreground(&mut command).and_then(|mut child| {
+        if common.verbose > 0 {
+            common.status(format_args!(
+                "connection spawned in {}ms",
+                start.elapsed().as_millis()
+            ));
+        }
+        child.wait()
+    });
+    drop(teardown);
+    let status = status?;
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+/// Connection event posted to the webhook
+#[derive(Serialize)]
+struct WebhookEvent<'a> {
+    subcommand: &'a str,
+    env: &'a str,
+    machine: &'a str,
+    user: Option<&'a str>,
+    timestamp: u64,
+}
+
+/// Post a connection event to the configured webhook, if any
+///
+/// This is best effort: the request is made by a curl process running in
+/// the background, and any failure is ignored so that it never prevents
+/// the connection itself.
+fn notify_webhook(
+    common: &CommonArgs,
+    resources: &Resource,
+    subcommand: &str,
+    target_env: &str,
+    machine_name: &str,
+    user: Option<&str>,
+) {
+    let url = match &resources.webhook_url {
+        Some(url) if !common.no_webhook && !common.dry_run => url,
+        _ => return,
+    };
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let payload = match serde_json::to_string(&WebhookEvent {
+        subcommand,
+        env: target_env,
+        machine: machine_name,
+        user,
+        timestamp,
+    }) {
+        Ok(payload) => payload,
+        Err(_) => return,
+    };
+    let _ = Command::new("curl")
+        .arg("--silent")
+        .arg("--max-time")
+        .arg("5")
+        .arg("-H")
+        .arg("Content-Type: application/json")
+        .arg("--data")
+        .arg(payload)
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+}
+
+/// Options of the shell subcommand
+#[derive(Clone, Debug, Default)]
+struct ShellOpts<'a> {
+    stop_at: Option<&'a str>,
+    verify_identity: bool,
+    /// Print how the connection is resolved instead of connecting
+    explain: bool,
+    record: Option<&'a Path>,
+    /// Connect with mosh, whatever the machine's backend
+    mosh: bool,
+    /// Address (`host[:port]`) connected to instead of a machine of the resources file
+    adhoc: Option<&'a str>,
+}
+
+/// Current one time password of a machine, with the seconds it stays valid
+fn otp_code(
+    envdef: &EnvironmentDef<ServerDef>,
+    machine_name: &str,
+) -> Result<Option<(String, u64)>> {
+    match &envdef.get_machine(machine_name)?.otp_secret {
+        None => Ok(None),
+        Some(secret) => {
+            let secret = env_value(secret)?;
+            let code = totp::code(&secret)
+                .with_context(|| format!("invalid otp_secret for {}", machine_name))?;
+            Ok(Some(code))
+        }
+    }
+}
+
+/// Print the one time passwords of the machines of the chain reaching a machine,
+/// to be pasted when ssh prompts for them
+fn show_otp(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let (full_name, _) = envdef.get_machine_entry(machine_name)?;
+    let mut chain = jump_chain(common, envdef, full_name)?;
+    chain.push(full_name);
+    for name in chain {
+        if let Some((code, valid)) = otp_code(envdef, name)? {
+            println!("one time password of {}: {} (valid {}s)", name, code, valid);
+        }
+    }
+    Ok(())
+}
+
+/// Print the current one time password of a machine
+fn otp(resources: &Resource, target_env: &str, machine_name: &str) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let (full_name, _) = envdef.get_machine_entry(machine_name)?;
+    match otp_code(envdef, full_name)? {
+        None => bail!("machine {} has no otp_secret defined", full_name),
+        Some((code, _)) => println!("{}", code),
+    }
+    Ok(())
+}
+
+/// Durations of a connection attempt
+#[derive(Clone, Copy, Debug)]
+struct Timing {
+    spawn: std::time::Duration,
+    first_byte: Option<std::time::Duration>,
+    total: std::time::Duration,
+}
+
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "spawned in {}ms", self.spawn.as_millis())?;
+        if let Some(first_byte) = self.first_byte {
+            write!(f, ", first byte after {}ms", first_byte.as_millis())?;
+        }
+        write!(f, ", done after {}ms", self.total.as_millis())
+    }
+}
+
+/// Run a command to completion like `Command::output`, timing its spawn and
+/// the first byte of its output (a proxy for the connection handshake)
+fn timed_output(command: &mut Command) -> std::io::Result<(std::process::Output, Timing)> {
+    use std::io::Read;
+    let start = std::time::Instant::now();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let spawn = start.elapsed();
+
+    let mut stderr_pipe = child.stderr.take().expect("piped stderr");
+    let stderr_reader = std::thread::spawn(move || {
+        let mut stderr = Vec::new();
+        stderr_pipe.read_to_end(&mut stderr).map(|_| stderr)
+    });
+
+    let mut stdout_pipe = child.stdout.take().expect("piped stdout");
+    let mut stdout = Vec::new();
+    let mut first_byte = None;
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stdout_pipe.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        first_byte.get_or_insert_with(|| start.elapsed());
+        stdout.extend_from_slice(&buf[..n]);
+    }
+
+    let status = child.wait()?;
+    let stderr = stderr_reader.join().expect("stderr reader")?;
+    let timing = Timing {
+        spawn,
+        first_byte,
+        total: start.elapsed(),
+    };
+    let output = std::process::Output {
+        status,
+        stdout,
+        stderr,
+    };
+    Ok((output, timing))
+}
+
+/// Network namespace to connect from, the command line taking precedence over the environment
+fn netns<'a>(common: &'a CommonArgs, resources: &'a Resource, target_env: &str) -> Option<&'a str> {
+    common.netns.as_deref().or_else(|| {
+        resources
+            .get_env_settings(target_env)
+            .and_then(|settings| settings.netns.as_deref())
+    })
+}
+
+/// Run the command in the network namespace, if any, with `ip netns exec`
+fn in_netns(netns: Option<&str>, command: Command) -> Result<Command> {
+    let netns = match netns {
+        None => return Ok(command),
+        Some(netns) => netns,
+    };
+    if !Path::new("/var/run/netns").join(netns).exists() {
+        bail!("network namespace {} doesn't exist", netns)
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        bail!(
+            "running in network namespace {} needs root privileges (ip netns exec)",
+            netns
+        )
+    }
+    let mut wrapped = Command::new("ip");
+    wrapped.args(["netns", "exec", netns]);
+    wrapped.arg(command.get_program());
+    wrapped.args(command.get_args());
+    Ok(wrapped)
+}
+
+/// Check that we are connecting to the expected machine
+///
+/// Run `uname -n` on the machine and check that the output contains the
+/// machine's `connect_banner_check`, to protect against DNS/IP mixups.
+fn verify_identity(
+    common: &CommonArgs,
+    netns: Option<&str>,
+    ssh_opt: &Ssh,
+    machine_def: &ServerDef,
+) -> Result<()> {
+    let expected = machine_def
+        .connect_banner_check
+        .as_ref()
+        .ok_or_else(|| anyhow!("no connect_banner_check defined for the machine"))?;
+
+    let mut command = Command::new("ssh");
+    command
+        .args(common.transport_args())
+        .args(&ssh_opt.args)
+        .arg(&ssh_opt.dest)
+        .arg("uname -n");
+    let mut command = in_netns(netns, command)?;
+    let (output, timing) = timed_output(&mut command).context("cannot run identity check")?;
+    if common.verbose > 0 {
+        common.status(format_args!("identity check connection: {}", timing));
+    }
+    if !output.status.success() {
+        bail!("identity check failed with {}", output.status)
+    }
+    let hostname = String::from_utf8_lossy(&output.stdout);
+    if !hostname.contains(expected.as_str()) {
+        bail!(
+            "identity check failed: expecting {} but machine is {}",
+            expected,
+            hostname.trim()
+        )
+    }
+    Ok(())
+}
+
+/// Check that a machine is reachable, by connecting to it to run `exit`
+fn ping(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+) -> Result<()> {
+    const PING_TIMEOUT: u32 = 5;
+
+    let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+    record_connection(common, "ping", target_env, machine_name, &ssh_opt)?;
+
+    let mut command = Command::new("ssh");
+    // a shorter timeout than the default one, unless one is given with -o
+    let timeout_given = has_ssh_option(&common.ssh_options, "ConnectTimeout");
+    command.args(common.transport_args()).args(
+        ssh_opt
+            .args
+            .iter()
+            .filter(|arg| timeout_given || !arg.starts_with("-oConnectTimeout=")),
+    );
+    if !timeout_given {
+        command.arg(format!("-oConnectTimeout={}", PING_TIMEOUT));
+    }
+    command
+        .arg("-oBatchMode=yes")
+        .arg(&ssh_opt.dest)
+        .arg("exit");
+    let mut command = in_netns(netns(common, resources, target_env), command)?;
+    if common.dry_run {
+        println!("{}", command_line(&command));
+        return Ok(());
+    }
+
+    let network = before_connect(common, resources, target_env, machine_name)?;
+    let start = std::time::Instant::now();
+    let output = command.output().context("cannot run ssh")?;
+    let elapsed = start.elapsed().as_millis();
+    drop(network);
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = match stderr.trim() {
+            "" => output.status.to_string(),
+            stderr => stderr.to_string(),
+        };
+        bail!(
+            "{} is not reachable ({}ms): {}",
+            machine_name,
+            elapsed,
+            reason
+        )
+    }
+    println!("{} is reachable ({}ms)", machine_name, elapsed);
+    Ok(())
+}
+
+fn shell(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+    opts: &ShellOpts,
+) -> Result<()> {
+    // when stopping at a hop, connect to the hop itself, reached through the
+    // part of the chain before it
+    let destination = machine_name;
+    let mut jumps = jump_chain(common, resources.get_target_env(target_env)?, machine_name)?;
+    let machine_name = match opts.stop_at {
+        None => machine_name,
+        Some(hop) => match jumps.iter().position(|j| j.as_str() == hop) {
+            None => bail!("{} is not a jump host used to reach {}", hop, machine_name),
+            Some(index) => {
+                jumps.truncate(index);
+                hop
+            }
+        },
+    };
+    let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+
+    let ssh_opt = ssh_login_through(common, resources, target_env, machine_name, &jumps)?;
+    write_trace(common, "shell", target_env, machine_name, &ssh_opt)?;
+    if opts.explain {
+        let mut command = Command::new("ssh");
+        command
+            .args(common.transport_args())
+            .args(&ssh_opt.args)
+            .arg(&ssh_opt.dest);
+        let command = in_netns(netns(common, resources, target_env), command)?;
+        return explain(
+            common,
+            resources,
+            target_env,
+            machine_name,
+            &jumps,
+            &ssh_opt,
+            &command,
+        );
+    }
+    write_log(common, "shell", target_env, machine_name, &ssh_opt)?;
+    warn_deprecated(resources, target_env, machine_name)?;
+    notify_webhook(
+        common,
+        resources,
+        "shell",
+        target_env,
+        machine_name,
+        user.as_deref(),
+    );
+    // the hop is reached through the same entry as the destination
+    let network = before_connect(common, resources, target_env, destination)?;
+
+    if opts.verify_identity && !common.dry_run {
+        let machine_def = resources
+            .get_target_env(target_env)?
+            .get_machine(machine_name)?;
+        verify_identity(
+            common,
+            netns(common, resources, target_env),
+            &ssh_opt,
+            machine_def,
+        )?;
+    }
+    show_otp(common, resources, target_env, machine_name)?;
+
+    common.status(format_args!(
+        "connecting target environment={} dest={}",
+        target_env, machine_name
+    ));
+
+    let machine_def = resources
+        .get_target_env(target_env)?
+        .get_machine(machine_name)?;
+    let backend = match opts.mosh {
+        true => Backend::Mosh,
+        false => machine_def.backend.unwrap_or(Backend::Ssh),
+    };
+    let command = match backend {
+        Backend::Ssh => {
+            let mut command = Command::new("ssh");
+            command.args(common.transport_args());
+
+            for a in ssh_opt.args.into_iter() {
+                command.arg(a);
+            }
+            if let Some(session) = &machine_def.tmux_session {
+                // the remote command needs a terminal, which ssh doesn't allocate by default
+                command.arg("-t");
+                command.arg(ssh_opt.dest);
+                command.arg(format!("tmux new -A -s {}", shell_quote(session)));
+            } else {
+                command.arg(ssh_opt.dest);
+            }
+            command
+        }
+        Backend::Et => et_command(common, resources, target_env, machine_name)?,
+        Backend::Mosh => mosh_command(common, &ssh_opt, machine_def.tmux_session.as_deref())?,
+    };
+    let command = in_netns(netns(common, resources, target_env), command)?;
+    let command = match opts.record {
+        None => command,
+        Some(file) => record(
+            common,
+            &command,
+            file,
+            target_env,
+            machine_name,
+            user.as_deref(),
+        )?,
+    };
+
+    let color = resources
+        .get_env_settings(target_env)
+        .and_then(|settings| settings.color.as_deref())
+        .filter(|_| !common.dry_run)
+        .and_then(TerminalColor::set);
+    run(
+        common,
+        command,
+        Teardown {
+            network,
+            color,
+            ..Teardown::default()
+        },
+    )
+}
+
+/// Shell on an address not described in the resources file (e.g. a machine
+/// being set up), with the known hosts file and username of the environment
+fn shell_adhoc(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    address: &str,
+    opts: &ShellOpts,
+) -> Result<()> {
+    let ssh_opt = ssh_login_adhoc(common, resources, target_env, address)?;
+    record_connection(common, "shell", target_env, address, &ssh_opt)?;
+    let user = ssh_opt.dest.split_once('@').map(|(user, _)| user);
+    notify_webhook(common, resources, "shell", target_env, address, user);
+
+    common.status(format_args!(
+        "connecting target environment={} dest={}",
+        target_env, address
+    ));
+
+    let mut command = Command::new("ssh");
+    command
+        .args(common.transport_args())
+        .args(&ssh_opt.args)
+        .arg(&ssh_opt.dest);
+    let command = in_netns(common.netns.as_deref(), command)?;
+    let command = match opts.record {
+        None => command,
+        Some(file) => record(common, &command, file, target_env, address, user)?,
+    };
+    run(common, command, Teardown::default())
+}
+
+/// Run a command on a machine, without interactive shell, exiting with its exit code
+///
+/// Like with ssh, the command's arguments are joined by spaces and interpreted
+/// by the remote shell.
+fn exec(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+    remote_command: &[&str],
+) -> Result<()> {
+    let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+
+    let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+    record_connection(common, "exec", target_env, machine_name, &ssh_opt)?;
+    warn_deprecated(resources, target_env, machine_name)?;
+    notify_webhook(
+        common,
+        resources,
+        "exec",
+        target_env,
+        machine_name,
+        user.as_deref(),
+    );
+    let network = before_connect(common, resources, target_env, machine_name)?;
+    show_otp(common, resources, target_env, machine_name)?;
+
+    let mut command = Command::new("ssh");
+    command.args(common.transport_args());
+    command.args(&ssh_opt.args);
+    // the remote command's options aren't taken as ssh ones
+    command.arg("--");
+    command.arg(ssh_opt.dest);
+    command.args(remote_command);
+    let command = in_netns(netns(common, resources, target_env), command)?;
+    run(
+        common,
+        command,
+        Teardown {
+            network,
+            ..Teardown::default()
+        },
+    )
+}
+
+/// Machines `exec --all` runs on: the ones listed (not the proxies), declaring
+/// the given operating system if any
+fn exec_machines<'a>(
+    resources: &'a Resource,
+    target_env: &str,
+    os: Option<&str>,
+) -> Result<Vec<&'a str>> {
+    let envdef = resources.get_target_env(target_env)?;
+    let mut machines: Vec<&str> = envdef
+        .list_non_proxies()
+        .filter(|(_, def)| os.map(|os| def.is_os(os)).unwrap_or(true))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    machines.sort_unstable();
+    if machines.is_empty() {
+        match os {
+            Some(os) => bail!("no machine of {} declares the os {}", target_env, os),
+            None => bail!("no machine in {}", target_env),
+        }
+    }
+    Ok(machines)
+}
+
+/// Run a command on the machines of the environment, `jobs` at a time
+///
+/// The output of each machine is printed once it's done, its lines prefixed
+/// by the machine name, and the machines on which the command failed are
+/// reported.
+fn exec_all(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    os: Option<&str>,
+    remote_command: &[&str],
+    jobs: usize,
+) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let mut tasks = Vec::new();
+    for machine_name in exec_machines(resources, target_env, os)? {
+        let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+        let mut command = Command::new("ssh");
+        command.args(common.transport_args());
+        command.args(&ssh_opt.args);
+        // nothing can be typed to ssh, so the machines have to be reached without a password
+        command.arg("-oBatchMode=yes");
+        command.arg("--");
+        command.arg(&ssh_opt.dest);
+        command.args(remote_command);
+        let command = in_netns(netns(common, resources, target_env), command)?;
+        if common.dry_run {
+            println!("{}", command_line(&command));
+            continue;
+        }
+
+        let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+        record_connection(common, "exec", target_env, machine_name, &ssh_opt)?;
+        warn_deprecated(resources, target_env, machine_name)?;
+        notify_webhook(
+            common,
+            resources,
+            "exec",
+            target_env,
+            machine_name,
+            user.as_deref(),
+        );
+        tasks.push(FanoutTask {
+            machine_name,
+            jump: envdef.jump_chain(machine_name)?.last().map(|j| j.as_str()),
+            command,
+        });
+    }
+    if common.dry_run {
+        return Ok(());
+    }
+
+    let _network = network_up(resources, target_env)?;
+    for task in tasks.iter() {
+        knock(common, resources, target_env, task.machine_name)?;
+    }
+    let limits = resources.jump_limits(target_env)?;
+    let mut failures = 0;
+    for (machine_name, result) in fanout(tasks, jobs, &limits, |mut command| {
+        timed_output(&mut command)
+    }) {
+        let output = match result {
+            Ok((output, _)) => output,
+            Err(e) => {
+                eprintln!("{}: cannot run ssh: {}", machine_name, e);
+                failures += 1;
+                continue;
+            }
+        };
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            println!("{}: {}", machine_name, line);
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            eprintln!("{}: {}", machine_name, line);
+        }
+        if !output.status.success() {
+            eprintln!("{}: {}", machine_name, output.status);
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        bail!("the command failed on {} machine(s)", failures)
+    }
+    Ok(())
+}
+
+/// Details of a recorded session, written next to the recording
+#[derive(Serialize)]
+struct RecordingMetadata<'a> {
+    env: &'a str,
+    machine: &'a str,
+    user: Option<&'a str>,
+    timestamp: u64,
+    recorder: &'a str,
+}
+
+/// Wrap the command in a session recorder (asciinema, or script) writing to `file`,
+/// with the details of the session written to `<file>.meta.json`
+fn record(
+    common: &CommonArgs,
+    command: &Command,
+    file: &Path,
+    target_env: &str,
+    machine_name: &str,
+    user: Option<&str>,
+) -> Result<Command> {
+    let (recorder, mut wrapped) = if in_path("asciinema") {
+        let mut wrapped = Command::new("asciinema");
+        wrapped
+            .arg("rec")
+            .arg("--command")
+            .arg(command_line(command));
+        ("asciinema", wrapped)
+    } else if in_path("script") {
+        let mut wrapped = Command::new("script");
+        wrapped
+            .arg("-q")
+            .arg("-e")
+            .arg("-c")
+            .arg(command_line(command));
+        ("script", wrapped)
+    } else {
+        bail!("recording needs asciinema or script to be installed")
+    };
+    wrapped.arg(file);
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let metadata = format!(
+        "{}\n",
+        serde_json::to_string(&RecordingMetadata {
+            env: target_env,
+            machine: machine_name,
+            user,
+            timestamp,
+            recorder,
+        })?
+    );
+    let mut metadata_file = file.as_os_str().to_owned();
+    metadata_file.push(".meta.json");
+    if common.dry_run {
+        return Ok(wrapped);
+    }
+    std::fs::write(&metadata_file, metadata).with_context(|| {
+        format!(
+            "cannot write the recording metadata {}",
+            Path::new(&metadata_file).display()
+        )
+    })?;
+    Ok(wrapped)
+}
+
+/// Warn on stderr when connecting to a deprecated machine
+fn warn_deprecated(resources: &Resource, target_env: &str, machine_name: &str) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let (full_name, def) = envdef.get_machine_entry(machine_name)?;
+    let warning = match &def.deprecated {
+        None | Some(Deprecated::Flag(false)) => return Ok(()),
+        Some(Deprecated::Flag(true)) => format!("{} is deprecated", full_name),
+        Some(Deprecated::Message(message)) => format!("{} is deprecated, {}", full_name, message),
+    };
+    if std::io::stderr().is_terminal() {
+        eprintln!("\x1b[1;33mwarning: {}\x1b[0m", warning);
+    } else {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(())
+}
+
+/// Check if a program can be found in the PATH
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
+
+/// mosh command connecting to a machine, bootstrapped by the ssh connection of machlist
+///
+/// The ssh options (jump hosts, known hosts file, ..) are packed in the ssh
+/// command given to mosh, which only uses it to start the server: the mosh
+/// client then talks directly to the machine over UDP.
+fn mosh_command(common: &CommonArgs, ssh_opt: &Ssh, tmux_session: Option<&str>) -> Result<Command> {
+    if !in_path("mosh") {
+        bail!("the mosh backend needs mosh to be installed")
+    }
+    let mut ssh = vec!["ssh".to_string()];
+    ssh.extend(common.transport_args());
+    ssh.extend(ssh_opt.args.iter().cloned());
+    let ssh: Vec<String> = ssh.iter().map(|arg| shell_quote(arg)).collect();
+
+    let mut command = Command::new("mosh");
+    command.arg(format!("--ssh={}", ssh.join(" ")));
+    command.arg(&ssh_opt.dest);
+    if let Some(session) = tmux_session {
+        command.args(["--", "tmux", "new", "-A", "-s", session]);
+    }
+    Ok(command)
+}
+
+/// Eternal Terminal command connecting to a machine, through its jump host if any
+/// (et having a single `--jumphost`, chains of jump hosts aren't supported)
+///
+/// et does its own ssh connection, so the ssh options of machlist aren't used
+fn et_command(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+) -> Result<Command> {
+    if !in_path("et") {
+        bail!("the et backend needs Eternal Terminal (et) to be installed")
+    }
+    let envdef = resources.get_target_env(target_env)?;
+    let user_host_of = |name: &str| -> Result<String> {
+        let def = envdef.get_machine(name)?;
+        let host =
+            def.host(common.prefer_family)?
+                .ok_or_else(|| MachlistError::MissingAddress {
+                    machine: name.to_string(),
+                    jump: false,
+                })?;
+        let user = resources.get_username(common.user.as_deref(), target_env, name)?;
+        Ok(user_host(user.as_deref(), &host))
+    };
+
+    let mut command = Command::new("et");
+    match jump_chain(common, envdef, machine_name)?.as_slice() {
+        [] => (),
+        [jump] => {
+            command.arg("--jumphost").arg(user_host_of(jump)?);
+        }
+        _ => bail!("the et backend supports a single jump host"),
+    }
+    command.arg(user_host_of(machine_name)?);
+    Ok(command)
+}
+
+/// Create the command used to copy files over the ssh transport
+///
+/// By default this is a plain scp, but when resuming we go through rsync
+/// (which needs to be installed on both ends) with the ssh arguments
+/// as the remote shell, so that partial transfers are kept and appended to.
+fn copy_command(common: &CommonArgs, ssh_args: &[String], resume: bool) -> Command {
+    if resume {
+        let mut transport = vec!["ssh".to_string()];
+        transport.extend(common.transport_args());
+        transport.extend(ssh_args.iter().cloned());
+        // rsync splits the transport command like a shell, so the arguments
+        // with spaces (e.g. a ProxyCommand) are quoted
+        let transport: Vec<String> = transport.iter().map(|arg| shell_quote(arg)).collect();
+
+        let mut command = Command::new("rsync");
+        command.arg("--partial");
+        command.arg("--append-verify");
+        command.arg("-e");
+        command.arg(transport.join(" "));
+        command
+    } else {
+        let mut command = Command::new("scp");
+        command.args(common.transport_args());
+        // scp takes the port with -P, -p preserving the modification times,
+        // and doesn't forward the agent (only recent versions know -A)
+        command.args(
+            ssh_args
+                .iter()
+                .filter(|a| *a != "-A")
+                .map(|a| if a == "-p" { "-P" } else { a }),
+        );
+        command
+    }
+}
+
+/// Remote path argument of copy-from
+///
+/// The remote path is interpreted by the remote shell, so it's quoted to be
+/// copied literally, unless we want the remote shell to expand it (`glob`)
+fn copy_from_path(ssh_opt: &Ssh, copy_path: &str, glob: bool) -> String {
+    if glob {
+        ssh_opt.remote_path(copy_path)
+    } else {
+        ssh_opt.remote_path(&shell_quote(copy_path))
+    }
+}
+
+/// Options of the copy-from subcommand
+#[derive(Clone, Debug, Default)]
+struct CopyFromOpts<'a> {
+    resume: bool,
+    glob: bool,
+    recursive: bool,
+    /// Local directory copied to, instead of the current one
+    dest: Option<&'a str>,
+}
+
+fn copy_from(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+    copy_paths: &[&str],
+    opts: &CopyFromOpts,
+) -> Result<()> {
+    let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+
+    let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+    record_connection(common, "copy-from", target_env, machine_name, &ssh_opt)?;
+    warn_deprecated(resources, target_env, machine_name)?;
+    notify_webhook(
+        common,
+        resources,
+        "copy-from",
+        target_env,
+        machine_name,
+        user.as_deref(),
+    );
+    let network = before_connect(common, resources, target_env, machine_name)?;
+
+    common.status(format_args!(
+        "connecting target environment={} dest={}",
+        target_env, machine_name
+    ));
+
+    let mut command = copy_command(common, &ssh_opt.args, opts.resume);
+    if opts.recursive {
+        command.arg("-r");
+    }
+    for copy_path in copy_paths {
+        command.arg(copy_from_path(&ssh_opt, copy_path, opts.glob));
+    }
+    // the local paths may not have been expanded by a shell
+    command.arg(expand_home(opts.dest.unwrap_or("./"))?);
+    let command = in_netns(netns(common, resources, target_env), command)?;
+    run(
+        common,
+        command,
+        Teardown {
+            network,
+            ..Teardown::default()
+        },
+    )
+}
+
+fn copy_to(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+    copy_path: &str,
+    resume: bool,
+) -> Result<()> {
+    let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+
+    let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+    record_connection(common, "copy-to", target_env, machine_name, &ssh_opt)?;
+    warn_deprecated(resources, target_env, machine_name)?;
+    notify_webhook(
+        common,
+        resources,
+        "copy-to",
+        target_env,
+        machine_name,
+        user.as_deref(),
+    );
+    let network = before_connect(common, resources, target_env, machine_name)?;
+
+    common.status(format_args!(
+        "connecting target environment={} dest={}",
+        target_env, machine_name
+    ));
+
+    let mut command = copy_command(common, &ssh_opt.args, resume);
+    let dst = ssh_opt.remote_path("");
+    command.arg(expand_home(copy_path)?);
+    command.arg(dst);
+    let command = in_netns(netns(common, resources, target_env), command)?;
+    run(
+        common,
+        command,
+        Teardown {
+            network,
+            ..Teardown::default()
+        },
+    )
+}
+
+/// Split a `machine:path` argument of copy-between
+fn machine_path(arg: &str) -> Result<(&str, &str)> {
+    match arg.split_once(':') {
+        Some((machine, path)) if !machine.is_empty() => Ok((machine, path)),
+        _ => bail!("invalid {}, expecting <machine>:<path>", arg),
+    }
+}
+
+/// Host aliases of the ends of copy-between, in its ssh config
+const COPY_SRC_HOST: &str = "machlist-src";
+const COPY_DST_HOST: &str = "machlist-dst";
+
+/// ssh config of copy-between for machines reached with different ssh options
+///
+/// scp applies its ssh options to both ends, so each end gets its options in
+/// its own Host block. The ssh config used otherwise (the environment's one, or
+/// else ~/.ssh/config) is included in each block, as `-F` replaces it.
+fn copy_between_config(common: &CommonArgs, src_opt: &Ssh, dst_opt: &Ssh) -> Result<String> {
+    let block = |alias: &str, ssh_opt: &Ssh| {
+        let mut args = ssh_opt.args.clone();
+        if !args.iter().any(|arg| arg == "-F") {
+            args.push("-F".to_string());
+            args.push("~/.ssh/config".to_string());
+        }
+        let ssh_opt = Ssh {
+            args,
+            dest: ssh_opt.dest.clone(),
+            trace: Vec::new(),
+        };
+        ssh_config_host(common, alias, &ssh_opt)
+    };
+    Ok(format!(
+        "{}\n\n{}\n",
+        block(COPY_SRC_HOST, src_opt)?,
+        block(COPY_DST_HOST, dst_opt)?
+    ))
+}
+
+/// Copy a file from a machine to another of the same environment, through the
+/// local host (scp -3)
+fn copy_between(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    (src_machine, src_path): (&str, &str),
+    (dst_machine, dst_path): (&str, &str),
+) -> Result<()> {
+    let src_opt = ssh_login(common, resources, target_env, src_machine)?;
+    let dst_opt = ssh_login(common, resources, target_env, dst_machine)?;
+
+    for (machine_name, ssh_opt) in [(src_machine, &src_opt), (dst_machine, &dst_opt)] {
+        let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+        record_connection(common, "copy-between", target_env, machine_name, ssh_opt)?;
+        warn_deprecated(resources, target_env, machine_name)?;
+        notify_webhook(
+            common,
+            resources,
+            "copy-between",
+            target_env,
+            machine_name,
+            user.as_deref(),
+        );
+    }
+    let network = before_connect(common, resources, target_env, src_machine)?;
+    if !common.dry_run {
+        knock(common, resources, target_env, dst_machine)?;
+    }
+
+    common.status(format_args!(
+        "connecting target environment={} src={} dest={}",
+        target_env, src_machine, dst_machine
+    ));
+
+    let (src_path, dst_path) = (shell_quote(src_path), shell_quote(dst_path));
+    let mut file = None;
+    let command = if src_opt.args == dst_opt.args {
+        let mut command = copy_command(common, &src_opt.args, false);
+        command.arg("-3");
+        command.arg(src_opt.remote_path(&src_path));
+        command.arg(dst_opt.remote_path(&dst_path));
+        command
+    } else {
+        let config = copy_between_config(common, &src_opt, &dst_opt)?;
+        let path = std::env::temp_dir().join(format!("machlist-{}.ssh_config", std::process::id()));
+        if common.dry_run {
+            println!("# {}\n{}", path.display(), config);
+        } else {
+            file = Some(TempFile::write(path.clone(), &config)?);
+        }
+        let mut command = copy_command(
+            common,
+            &["-F".to_string(), path.display().to_string()],
+            false,
+        );
+        command.arg("-3");
+        command.arg(format!("{}:{}", COPY_SRC_HOST, src_path));
+        command.arg(format!("{}:{}", COPY_DST_HOST, dst_path));
+        command
+    };
+    let command = in_netns(netns(common, resources, target_env), command)?;
+    run(
+        common,
+        command,
+        Teardown {
+            network,
+            file,
+            ..Teardown::default()
+        },
+    )
+}
+
+/// Options of the tunnel subcommand
+#[derive(Clone, Debug, Default)]
+struct TunnelOpts<'a> {
+    local_port: Option<&'a str>,
+    family: Option<AddressFamily>,
+    watch_config: bool,
+    check: bool,
+    hosts_alias: bool,
+    reconnect: bool,
+    connect: bool,
+    /// Forward the resource's port on its machine to the local port (-R)
+    reverse: bool,
+    /// Local address the tunnel listens on, instead of localhost
+    bind_address: Option<&'a str>,
+    /// Detach the tunnel from the terminal instead of waiting for it
+    background: bool,
+    /// Print how the connection is resolved instead of connecting
+    explain: bool,
+}
+
+/// Resolve the resources to tunnel, which have to be on the same machine
+/// to be forwarded by a single ssh
+fn tunnel_resources<'a>(
+    resources: &'a Resource,
+    target_env: &str,
+    resource_names: &[&str],
+) -> Result<Vec<(&'a str, &'a ResourceDef)>> {
+    let defs = resources.get_target_env_resources(target_env)?;
+    let mut tunneled: Vec<(&str, &ResourceDef)> = Vec::new();
+    for name in resource_names {
+        let (name, def) = defs.get_resource_entry(name)?;
+        if def.port == 0 || def.local_port == Some(0) {
+            bail!("resource {} has a port 0, which can't be tunneled", name)
+        }
+        if let Some((first, first_def)) = tunneled.first() {
+            if first_def.server != def.server {
+                bail!(
+                    "resources {} and {} are on different machines ({} and {}), they can't be tunneled together",
+                    first,
+                    name,
+                    first_def.server,
+                    def.server
+                )
+            }
+        }
+        tunneled.push((name, def));
+    }
+    Ok(tunneled)
+}
+
+fn tunnel(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    resource_names: &[&str],
+    opts: &TunnelOpts,
+) -> Result<()> {
+    let local_port = opts
+        .local_port
+        .map(|x| {
+            x.parse::<u16>()
+                .map_err(|e| anyhow!("invalid local port '{}': {}", x, e))
+        })
+        .transpose()?;
+    if local_port.is_some() && resource_names.len() > 1 {
+        bail!("a local port can only be given when tunneling a single resource")
+    }
+    let (bind_ip, local_address) = tunnel_local_address(opts.bind_address)?;
+
+    let defs = tunnel_resources(resources, target_env, resource_names)?;
+    let local_port_of = |def: &ResourceDef| local_port.unwrap_or(def.default_local_port());
+    for (i, (name, def)) in defs.iter().enumerate() {
+        if let Some((other, _)) = defs[..i]
+            .iter()
+            .find(|(_, other)| local_port_of(other) == local_port_of(def))
+        {
+            eprintln!(
+                "warning: resources {} and {} are tunneled on the same local port {}",
+                other,
+                name,
+                local_port_of(def)
+            );
+        }
+    }
+
+    let machine_name = &defs[0].1.server;
+    let user = resources.get_username(common.user.as_deref(), target_env, machine_name)?;
+    let client = match (opts.connect, defs.as_slice()) {
+        (false, _) => None,
+        (true, [(_, def)]) => match &def.client {
+            Some(client) => Some((client, local_port_of(def))),
+            None => bail!(
+                "resource {} has no client command defined",
+                resource_names[0]
+            ),
+        },
+        (true, _) => bail!("--connect can only be used with a single resource"),
+    };
+    if client.is_some() && netns(common, resources, target_env).is_some() {
+        bail!("--connect cannot be used in a network namespace, the tunnel being only reachable from it")
+    }
+    if opts.background
+        && resources
+            .get_env_settings(target_env)
+            .is_some_and(|settings| {
+                settings.network_up.is_some() || settings.network_down.is_some()
+            })
+    {
+        bail!("--background cannot be used in an environment with network_up or network_down, the network being managed by machlist while it runs")
+    }
+
+    let command = tunnel_prepare(
+        common,
+        resources,
+        target_env,
+        resource_names,
+        local_port,
+        opts,
+    )?;
+
+    if opts.explain {
+        let envdef = resources.get_target_env(target_env)?;
+        let jumps = jump_chain(common, envdef, machine_name)?;
+        let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+        explain(
+            common,
+            resources,
+            target_env,
+            machine_name,
+            &jumps,
+            &ssh_opt,
+            &command,
+        )?;
+        for (resource_name, def) in defs.iter() {
+            if opts.reverse {
+                println!(
+                    "resource {}: port {} of {} to local port {}",
+                    resource_name,
+                    def.port,
+                    machine_name,
+                    local_port_of(def)
+                );
+            } else {
+                println!(
+                    "resource {}: {} port {} on local port {}",
+                    resource_name,
+                    def.at()?,
+                    def.port,
+                    local_port_of(def)
+                );
+            }
+        }
+        return Ok(());
+    }
+    if opts.check {
+        // a reverse tunnel forwards to a local service, which is expected to be listening
+        for (_, def) in defs.iter().filter(|_| !opts.reverse) {
+            let local_port = local_port_of(def);
+            std::net::TcpListener::bind((bind_ip, local_port))
+                .with_context(|| format!("local port {} is not available", local_port))?;
+        }
+        println!("would run: {}", command_line(&command));
+        return Ok(());
+    }
+    if common.dry_run {
+        println!("{}", command_line(&command));
+        return Ok(());
+    }
+
+    notify_webhook(
+        common,
+        resources,
+        "tunnel",
+        target_env,
+        machine_name,
+        user.as_deref(),
+    );
+    let network = before_connect(common, resources, target_env, machine_name)?;
+    show_otp(common, resources, target_env, machine_name)?;
+    warn_deprecated(resources, target_env, machine_name)?;
+    let mut hosts = Vec::new();
+    if opts.hosts_alias {
+        for (_, def) in defs.iter() {
+            if def.at()?.parse::<IpAddr>().is_ok() {
+                bail!("cannot alias {}, it's not a name", def.at()?)
+            }
+            hosts.push(HostsEntry::add_local(def.at()?)?);
+        }
+    }
+    let teardown = Teardown {
+        network,
+        hosts,
+        ..Teardown::default()
+    };
+
+    for (resource_name, def) in defs.iter() {
+        if opts.reverse {
+            common.status(format_args!(
+                "reverse tunneling target environment={} resource={} from port {} of {} to local port {}",
+                target_env,
+                resource_name,
+                def.port,
+                machine_name,
+                local_port_of(def)
+            ));
+        } else {
+            common.status(format_args!(
+                "forward tunneling target environment={} resource={} via {} on local port {}",
+                target_env,
+                resource_name,
+                machine_name,
+                local_port_of(def)
+            ));
+        }
+    }
+
+    if opts.background {
+        // readiness can only be checked on the local end of forward tunnels
+        let local_ports: Vec<u16> = match opts.reverse {
+            true => Vec::new(),
+            false => defs.iter().map(|(_, def)| local_port_of(def)).collect(),
+        };
+        let names: Vec<&str> = defs.iter().map(|(name, _)| *name).collect();
+        return tunnel_background(command, target_env, &names, local_address, &local_ports);
+    }
+    if opts.watch_config || opts.reconnect {
+        let prepare = |resources: &Resource| {
+            tunnel_prepare(
+                common,
+                resources,
+                target_env,
+                resource_names,
+                local_port,
+                opts,
+            )
+        };
+        let status = tunnel_supervise(common, command, prepare, opts.watch_config, opts.reconnect);
+        drop(teardown);
+        std::process::exit(status?.code().unwrap_or(1))
+    }
+    if let Some((client, local_port)) = client {
+        let host = match opts.bind_address {
+            None => "localhost".to_string(),
+            Some(_) => local_address.to_string(),
+        };
+        return tunnel_connect(
+            common,
+            command,
+            client,
+            &host,
+            (local_address, local_port),
+            teardown,
+        );
+    }
+    run(common, command, teardown)
+}
+
+/// Address the tunnel binds, and the one its local end is reached on: the
+/// bind address, or the loopback when it's unspecified (all the interfaces)
+fn tunnel_local_address(bind_address: Option<&str>) -> Result<(IpAddr, IpAddr)> {
+    let bind_ip = match bind_address {
+        None => return Ok((Ipv4Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into())),
+        Some(address) => (unbracket(address), 0)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| {
+                anyhow!(
+                    "invalid bind address {}, expecting an IP or a resolvable host",
+                    address
+                )
+            })?
+            .ip(),
+    };
+    let local_address = match bind_ip {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    Ok((bind_ip, local_address))
+}
+
+/// Start the tunnel detached from the terminal, and print its PID once the
+/// local ports accept connections
+///
+/// The PID is recorded in `~/.machlist/tunnels/<env>-<resource>.pid` for each
+/// resource, and the output of ssh is written to `<env>-<resources>.log` there.
+fn tunnel_background(
+    mut command: Command,
+    target_env: &str,
+    resource_names: &[&str],
+    local_address: IpAddr,
+    local_ports: &[u16],
+) -> Result<()> {
+    use std::time::{Duration, Instant};
+    const START_TIMEOUT: Duration = Duration::from_secs(15);
+    const START_DELAY: Duration = Duration::from_secs(1);
+
+    // a port already bound would be taken for the tunnel being ready
+    for port in local_ports {
+        std::net::TcpListener::bind((local_address, *port))
+            .with_context(|| format!("local port {} is not available", port))?;
+    }
+
+    let dir = machlist_tunnels_dir()?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let log_file = dir.join(format!("{}-{}.log", target_env, resource_names.join(",")));
+    let log = std::fs::File::create(&log_file)
+        .with_context(|| format!("cannot create {}", log_file.display()))?;
+
+    // SAFETY: only async-signal-safe calls are made between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            // a new session, so that the tunnel doesn't get the signals of the terminal
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let mut tunnel = command
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .context("cannot start the tunnel")?;
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = tunnel.try_wait()? {
+            bail!("tunnel terminated ({}), see {}", status, log_file.display())
+        }
+        let ready = if local_ports.is_empty() {
+            started.elapsed() > START_DELAY
+        } else {
+            local_ports
+                .iter()
+                .all(|port| std::net::TcpStream::connect((local_address, *port)).is_ok())
+        };
+        if ready {
+            break;
+        }
+        if started.elapsed() > START_TIMEOUT {
+            let _ = tunnel.kill();
+            let _ = tunnel.wait();
+            bail!(
+                "tunnel not ready after {}s, see {}",
+                START_TIMEOUT.as_secs(),
+                log_file.display()
+            )
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    let pid = tunnel.id();
+    for resource_name in resource_names {
+        let pid_file = dir.join(format!("{}-{}.pid", target_env, resource_name));
+        std::fs::write(&pid_file, format!("{}\n", pid))
+            .with_context(|| format!("cannot write {}", pid_file.display()))?;
+    }
+    println!("{}", pid);
+    Ok(())
+}
+
+/// Open the tunnel in the background, and run the client command against it
+/// until it exits
+fn tunnel_connect(
+    common: &CommonArgs,
+    mut command: Command,
+    client: &str,
+    host: &str,
+    (local_address, local_port): (IpAddr, u16),
+    teardown: Teardown,
+) -> Result<()> {
+    use std::time::{Duration, Instant};
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+    let mut tunnel = command
+        .stdin(std::process::Stdio::null())
+        .spawn()
+        .context("cannot start the tunnel")?;
+
+    // wait for the local end of the tunnel to accept connections, which ssh
+    // only binds once connected
+    let started = Instant::now();
+    loop {
+        if let Some(status) = tunnel.try_wait()? {
+            bail!(
+                "tunnel terminated ({}) before the client could connect",
+                status
+            )
+        }
+        if std::net::TcpStream::connect((local_address, local_port)).is_ok() {
+            if common.verbose > 0 {
+                common.status(format_args!(
+                    "tunnel ready after {}ms",
+                    started.elapsed().as_millis()
+                ));
+            }
+            break;
+        }
+        if started.elapsed() > CONNECT_TIMEOUT {
+            let _ = tunnel.kill();
+            bail!(
+                "tunnel not ready on port {} after {}s",
+                local_port,
+                CONNECT_TIMEOUT.as_secs()
+            )
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    let client = client
+        .replace("{host}", host)
+        .replace("{port}", &local_port.to_string());
+    let status = spawn_foreground(Command::new("sh").arg("-c").arg(&client))
+        .and_then(|mut child| child.wait());
+
+    let _ = tunnel.kill();
+    let _ = tunnel.wait();
+    drop(teardown);
+    let status = status.with_context(|| format!("cannot run client: {}", client))?;
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+/// Resolve the resources and create the command tunneling to them
+fn tunnel_prepare(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    resource_names: &[&str],
+    local_port: Option<u16>,
+    opts: &TunnelOpts,
+) -> Result<Command> {
+    let defs = tunnel_resources(resources, target_env, resource_names)?;
+    let mut forwards = Vec::new();
+    for (_, def) in defs.iter() {
+        if let (Some(family), false) = (opts.family, opts.reverse) {
+            def.check_family(family)?;
+        }
+        forwards.push((*def, local_port.unwrap_or(def.default_local_port())));
+    }
+
+    let server = &defs[0].1.server;
+    let ssh_opt = ssh_login(common, resources, target_env, server)?;
+    // explaining or checking the tunnel doesn't connect, so isn't logged
+    if !(opts.explain || opts.check) {
+        write_log(common, "tunnel", target_env, server, &ssh_opt)?;
+    }
+    write_trace(common, "tunnel", target_env, server, &ssh_opt)?;
+    let command = tunnel_command(common, ssh_opt, &forwards, opts)?;
+    in_netns(netns(common, resources, target_env), command)
+}
+
+static INTERRUPTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Run the tunnel, keeping it up
+///
+/// With `watch_config`, when a change of the resources file changes the
+/// command needed to reach the resource (e.g. the machine's address changed),
+/// the tunnel is torn down and re-established with the new command.
+///
+/// With `reconnect`, the tunnel is re-established when it terminates, with an
+/// increasing delay between attempts, until interrupted.
+///
+/// Returns when the tunnel terminates and isn't re-established.
+fn tunnel_supervise<F>(
+    common: &CommonArgs,
+    command: Command,
+    prepare: F,
+    watch_config: bool,
+    reconnect: bool,
+) -> Result<ExitStatus>
+where
+    F: Fn(&Resource) -> Result<Command>,
+{
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+    const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    let modified = || {
+        std::fs::metadata(&common.res_file)
+            .and_then(|m| m.modified())
+            .ok()
+    };
+
+    let mut last_modified = modified();
+    let mut last_watch = Instant::now();
+    let mut backoff = Duration::from_secs(1);
+    let mut current = command;
+    let mut started = Instant::now();
+    // spawning resets SIGINT to ignored, so interruptions are noted again after each spawn
+    let spawn = |command: &mut Command| -> Result<Child> {
+        let child = spawn_foreground(command)?;
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                on_interrupt as *const () as libc::sighandler_t,
+            );
+        }
+        Ok(child)
+    };
+    let mut child = spawn(&mut current)?;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !reconnect || INTERRUPTED.load(Ordering::SeqCst) {
+                return Ok(status);
+            }
+            // the tunnel stayed up for a while, so it's a new failure
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = Duration::from_secs(1);
+            }
+            if common.verbose > 0 {
+                common.status(format_args!(
+                    "tunnel terminated ({}), reconnecting in {}s",
+                    status,
+                    backoff.as_secs()
+                ));
+            }
+            let wait_until = Instant::now() + backoff;
+            while Instant::now() < wait_until {
+                if INTERRUPTED.load(Ordering::SeqCst) {
+                    return Ok(status);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            if common.verbose > 0 {
+                common.status(format_args!("reconnecting tunnel"));
+            }
+            started = Instant::now();
+            child = spawn(&mut current)?;
+            continue;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+
+        if !watch_config || last_watch.elapsed() < WATCH_INTERVAL {
+            continue;
+        }
+        last_watch = Instant::now();
+        let now_modified = modified();
+        if now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
+
+        let new_command = match parse_resources(&common.res_file)
+            .map_err(anyhow::Error::from)
+            .and_then(|r| prepare(&r))
+        {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("ignoring change of resources file: {:#}", e);
+                continue;
+            }
+        };
+        if command_line(&new_command) == command_line(&current) {
+            continue;
+        }
+
+        common.status(format_args!(
+            "resources file changed, re-establishing tunnel"
+        ));
+        let _ = child.kill();
+        child.wait()?;
+        current = new_command;
+        started = Instant::now();
+        child = spawn(&mut current)?;
+    }
+}
+
+/// Create the ssh command forwarding the local ports to the resources, or the
+/// resources' ports on their machine to the local ports when `reverse`
+fn tunnel_command(
+    common: &CommonArgs,
+    ssh_opt: Ssh,
+    forwards: &[(&ResourceDef, u16)],
+    opts: &TunnelOpts,
+) -> Result<Command> {
+    let mut command = Command::new("ssh");
+    command.args(common.transport_args());
+    if let Some(family) = opts.family {
+        command.arg(family.ssh_arg());
+    }
+
+    for a in ssh_opt.args.into_iter() {
+        command.arg(a);
+    }
+
+    command.arg("-N"); // do not execute a remote command
+    for (def, local_port) in forwards {
+        if opts.reverse {
+            command.arg("-R");
+            command.arg(def.reverse_forwarding(*local_port));
+        } else {
+            command.arg("-L");
+            match opts.bind_address {
+                None => command.arg(def.forwarding(*local_port)?),
+                Some(address) => command.arg(format!(
+                    "{}:{}",
+                    bracket_ipv6(address),
+                    def.forwarding(*local_port)?
+                )),
+            };
+        }
+    }
+
+    command.arg(ssh_opt.dest);
+    Ok(command)
+}
+
+/// Quote a string for a POSIX shell, leaving it as is when it's safe
+fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@,+%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Render a command as a shell command line
+fn command_line(command: &Command) -> String {
+    let mut line = shell_quote(&command.get_program().to_string_lossy());
+    for arg in command.get_args() {
+        line.push(' ');
+        line.push_str(&shell_quote(&arg.to_string_lossy()));
+    }
+    line
+}
+
+/// Check the names of the environments, machines and resources against the naming policy
+fn check_naming(resources: &Resource, policy: &Pattern) -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut check = |location: String, name: &str| {
+        if !policy.is_match(name) {
+            violations.push(format!(
+                "{} name `{}` doesn't match {}",
+                location,
+                name,
+                policy.as_str()
+            ));
+        }
+    };
+
+    let mut envs: Vec<&str> = sorted_keys(&resources.server);
+    envs.extend(sorted_keys(&resources.resource));
+    envs.extend(sorted_keys(&resources.environment));
+    envs.sort_unstable();
+    envs.dedup();
+    for env in envs {
+        check("environment".to_string(), env);
+    }
+    for env in sorted_keys(&resources.server) {
+        for machine in sorted_keys(&resources.server[env].0) {
+            check(format!("server.{}: machine", env), machine);
+        }
+    }
+    for env in sorted_keys(&resources.resource) {
+        for resource in sorted_keys(&resources.resource[env].0) {
+            check(format!("resource.{}: resource", env), resource);
+        }
+    }
+    violations
+}
+
+/// Jumps and resources referencing a machine missing from their environment,
+/// resource groups referencing a missing resource, and machines with both a
+/// jump and a proxy command
+fn check_integrity(resources: &Resource) -> Vec<String> {
+    let mut problems = Vec::new();
+    for env in sorted_keys(&resources.server) {
+        let machines = &resources.server[env].0;
+        for name in sorted_keys(machines) {
+            if machines[name].proxy_command.is_some() && !machines[name].jumps().is_empty() {
+                problems.push(format!(
+                    "server.{}.{}: jump and proxy_command can't both be set",
+                    env, name
+                ));
+            }
+            for jump in machines[name].jumps() {
+                if !machines.contains_key(jump) {
+                    problems.push(format!(
+                        "server.{}.{}: jump `{}` doesn't exist",
+                        env, name, jump
+                    ));
+                }
+            }
+        }
+    }
+    for env in sorted_keys(&resources.resource) {
+        let machines = resources.server.get(env).map(|envdef| &envdef.0);
+        for name in sorted_keys(&resources.resource[env].0) {
+            let def = &resources.resource[env].0[name];
+            if !machines.is_some_and(|m| m.contains_key(&def.server)) {
+                problems.push(format!(
+                    "resource.{}.{}: server `{}` doesn't exist",
+                    env, name, def.server
+                ));
+            }
+            if def.port == 0 || def.local_port == Some(0) {
+                problems.push(format!(
+                    "resource.{}.{}: port 0 can't be tunneled",
+                    env, name
+                ));
+            }
+        }
+    }
+    for env in sorted_keys(&resources.resource_group) {
+        let defs = resources.resource.get(env).map(|envdef| &envdef.0);
+        for group in sorted_keys(&resources.resource_group[env].0) {
+            for member in resources.resource_group[env].0[group].iter() {
+                if !defs.is_some_and(|d| d.contains_key(member)) {
+                    problems.push(format!(
+                        "resource_group.{}.{}: resource `{}` doesn't exist",
+                        env, group, member
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Likely mistakes: resources hosted on a proxy or sharing a local port, jumps
+/// not marked as proxy, and machines referenced by no resource nor jump (which
+/// may just be connected to directly)
+fn check_references(resources: &Resource) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for env in sorted_keys(&resources.resource) {
+        let machines = resources.server.get(env).map(|envdef| &envdef.0);
+        for name in sorted_keys(&resources.resource[env].0) {
+            let server = &resources.resource[env].0[name].server;
+            let is_proxy = machines
+                .and_then(|m| m.get(server))
+                .is_some_and(|def| def.proxy.unwrap_or(false));
+            if is_proxy {
+                warnings.push(format!(
+                    "resource.{}: resource `{}` is on `{}`, which is a proxy",
+                    env, name, server
+                ));
+            }
+        }
+    }
+    for env in sorted_keys(&resources.resource) {
+        let mut by_port: std::collections::BTreeMap<u16, Vec<&str>> = Default::default();
+        for name in sorted_keys(&resources.resource[env].0) {
+            let port = resources.resource[env].0[name].default_local_port();
+            by_port.entry(port).or_default().push(name);
+        }
+        for (port, names) in by_port.iter().filter(|(_, names)| names.len() > 1) {
+            warnings.push(format!(
+                "resource.{}: resources `{}` are tunneled on the same local port {}, set a distinct `local_port` to open them together",
+                env,
+                names.join("`, `"),
+                port
+            ));
+        }
+    }
+    for env in sorted_keys(&resources.server) {
+        let machines = &resources.server[env].0;
+        let referenced = |name: &str| {
+            machines
+                .values()
+                .any(|def| def.jumps().iter().any(|jump| jump == name))
+                || resources
+                    .resource
+                    .get(env)
+                    .is_some_and(|defs| defs.0.values().any(|def| def.server == name))
+        };
+        let jumps: std::collections::BTreeSet<&String> =
+            machines.values().flat_map(|def| def.jumps()).collect();
+        for jump in jumps {
+            let is_proxy = machines
+                .get(jump)
+                .is_some_and(|def| def.proxy.unwrap_or(false));
+            if machines.contains_key(jump) && !is_proxy {
+                warnings.push(format!(
+                    "server.{}: machine `{}` is used as a jump but isn't marked `proxy = true`, so it shows up in list",
+                    env, jump
+                ));
+            }
+        }
+        for name in sorted_keys(machines) {
+            if !referenced(name) {
+                warnings.push(format!(
+                    "server.{}: machine `{}` isn't used by any resource or jump",
+                    env, name
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+fn config_check(resources: &Resource) -> Result<()> {
+    let mut problems = check_integrity(resources);
+    if let Some(naming_policy) = &resources.naming_policy {
+        let policy = Pattern::new(naming_policy)?;
+        problems.extend(check_naming(resources, &policy));
+    }
+
+    for warning in check_references(resources) {
+        println!("warning: {}", warning);
+    }
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("{} problem(s) found in the configuration", problems.len())
+    }
+    println!("configuration ok");
+    Ok(())
+}
+
+/// Command scanning the host keys of a machine, run from its jump host if any
+fn keyscan_command(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+) -> Result<Command> {
+    const KEYSCAN_TIMEOUT: &str = "5";
+    let envdef = resources.get_target_env(target_env)?;
+    let machine_def = envdef.get_machine(machine_name)?;
+    let host =
+        machine_def
+            .host(common.prefer_family)?
+            .ok_or_else(|| MachlistError::MissingAddress {
+                machine: machine_name.to_string(),
+                jump: false,
+            })?;
+
+    let port = machine_def.port.unwrap_or(DEFAULT_SSH_PORT).to_string();
+
+    // the last jump host scans the machine, reached through the hops before it
+    let chain = envdef.jump_chain(machine_name)?;
+    let command = match chain.split_last() {
+        None => {
+            let mut command = Command::new("ssh-keyscan");
+            command.args(["-T", KEYSCAN_TIMEOUT, "-p", &port, &host]);
+            command
+        }
+        Some((jump, hops)) => {
+            let ssh_opt = ssh_login_through(common, resources, target_env, jump, hops)?;
+            let mut command = Command::new("ssh");
+            command.args(common.transport_args());
+            command.args(&ssh_opt.args);
+            command.arg("-oBatchMode=yes");
+            command.arg(ssh_opt.dest);
+            command.args(["ssh-keyscan", "-T", KEYSCAN_TIMEOUT, "-p", &port, &host]);
+            command
+        }
+    };
+    in_netns(netns(common, resources, target_env), command)
+}
+
+/// Command to run on a machine, possibly through a jump host
+struct FanoutTask<'a> {
+    machine_name: &'a str,
+    jump: Option<&'a str>,
+    command: Command,
+}
+
+/// Run the tasks, at most `jobs` at a time and at most the limit of a jump
+/// host through it, returning the results sorted by machine
+fn fanout<'a, T, F>(
+    tasks: Vec<FanoutTask<'a>>,
+    jobs: usize,
+    limits: &HashMap<&str, usize>,
+    f: F,
+) -> Vec<(&'a str, T)>
+where
+    T: Send,
+    F: Fn(Command) -> T + Sync,
+{
+    use std::sync::{Condvar, Mutex};
+    // pending tasks, and the number of tasks running through each jump host
+    let state = Mutex::new((tasks, HashMap::<&str, usize>::new()));
+    let changed = Condvar::new();
+    let results = Mutex::new(Vec::new());
+
+    let under_limit = |running: &HashMap<&str, usize>, task: &FanoutTask| match task.jump {
+        None => true,
+        Some(jump) => match limits.get(jump) {
+            None => true,
+            Some(limit) => running.get(jump).copied().unwrap_or(0) < (*limit).max(1),
+        },
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let task = {
+                    let mut state = state.lock().unwrap();
+                    loop {
+                        let (pending, running) = &mut *state;
+                        if pending.is_empty() {
+                            break None;
+                        }
+                        if let Some(i) = pending.iter().position(|t| under_limit(running, t)) {
+                            let task = pending.remove(i);
+                            if let Some(jump) = task.jump {
+                                *running.entry(jump).or_insert(0) += 1;
+                            }
+                            break Some(task);
+                        }
+                        state = changed.wait(state).unwrap();
+                    }
+                };
+                let task = match task {
+                    None => break,
+                    Some(task) => task,
+                };
+
+                let result = f(task.command);
+                results.lock().unwrap().push((task.machine_name, result));
+
+                if let Some(jump) = task.jump {
+                    let mut state = state.lock().unwrap();
+                    if let Some(n) = state.1.get_mut(jump) {
+                        *n -= 1;
+                    }
+                }
+                changed.notify_all();
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|a, b| a.0.cmp(b.0));
+    results
+}
+
+/// Keys found by a scan of host keys
+fn keyscan_result(mut command: Command) -> Result<(String, Timing)> {
+    let (output, timing) = timed_output(&mut command)?;
+    let keys = String::from_utf8_lossy(&output.stdout).into_owned();
+    if keys.lines().any(|l| !l.is_empty() && !l.starts_with('#')) {
+        Ok((keys, timing))
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().rfind(|l| !l.starts_with('#'));
+        Err(anyhow!("{}", reason.unwrap_or("no host key found")))
+    }
+}
+
+/// Refresh the known hosts of the environment with the keys scanned on its machines
+///
+/// The keys of the scanned machines replace their previous entries. With `all`,
+/// every machine is scanned and the file rebuilt from scratch, unless `append`
+/// is set to keep the entries of the other hosts. The machines reached directly
+/// are scanned first, so that their fresh keys are used to connect to the jump
+/// hosts of the others.
+fn trust(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine: Option<&str>,
+    append: bool,
+    jobs: usize,
+) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let path = known_hosts_file(common, resources, target_env)?;
+
+    let mut kept: Vec<String> = if machine.is_none() && !append {
+        Vec::new()
+    } else {
+        match std::fs::read_to_string(&path) {
+            Ok(content) => content.lines().map(|l| l.to_string()).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        }
+    };
+
+    let machines: Vec<&str> = match machine {
+        Some(machine_name) => vec![envdef.get_machine_entry(machine_name)?.0.as_str()],
+        None => sorted_keys(&envdef.0),
+    };
+    let (direct, jumped): (Vec<&str>, Vec<&str>) = machines
+        .into_iter()
+        .partition(|m| envdef.jump_chain(m).is_ok_and(|chain| chain.is_empty()));
+
+    let limits = resources.jump_limits(target_env)?;
+    let mut scanned = Vec::new();
+    let mut failures = 0;
+    for machines in [direct, jumped] {
+        if machines.is_empty() {
+            continue;
+        }
+        let mut scans = Vec::new();
+        for machine_name in machines {
+            scans.push(FanoutTask {
+                machine_name,
+                jump: envdef.jump_chain(machine_name)?.last().map(|j| j.as_str()),
+                command: keyscan_command(common, resources, target_env, machine_name)?,
+            });
+        }
+        if common.dry_run {
+            for scan in scans.iter() {
+                println!("{}", command_line(&scan.command));
+            }
+            continue;
+        }
+
+        for (machine_name, result) in fanout(scans, jobs, &limits, keyscan_result) {
+            let keys = match result {
+                Ok((keys, timing)) => {
+                    if common.verbose > 0 {
+                        println!("trusting {} ({})", machine_name, timing);
+                    } else {
+                        println!("trusting {}", machine_name);
+                    }
+                    keys
+                }
+                Err(e) => {
+                    eprintln!("cannot scan {}: {}", machine_name, e);
+                    failures += 1;
+                    continue;
+                }
+            };
+            let def = envdef.get_machine(machine_name)?;
+            if let Some(host) = def.host(common.prefer_family)? {
+                // known hosts name the hosts on other ports `[host]:port`
+                let host = match def.port {
+                    Some(port) if port != DEFAULT_SSH_PORT => format!("[{}]:{}", host, port),
+                    _ => host,
+                };
+                kept.retain(|line| {
+                    let hosts = line.split_whitespace().next().unwrap_or("");
+                    !hosts.split(',').any(|h| h == host)
+                });
+            }
+            scanned.extend(
+                keys.lines()
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(|l| l.to_string()),
+            );
+        }
+
+        let mut content = kept
+            .iter()
+            .chain(scanned.iter())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("\n");
+        content.push('\n');
+        std::fs::write(&path, content)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+    }
+
+    if failures > 0 {
+        bail!("{} machines could not be scanned", failures)
+    }
+    Ok(())
+}
+
+/// Add the host keys of the machines of the environment missing from its known hosts
+///
+/// The machines listed (not the proxies) are scanned, along with the jump hosts
+/// they are reached through, which are scanned first so that the others can be
+/// scanned through them. Entries already known are left as is.
+fn known_hosts_sync(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    jobs: usize,
+) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let path = known_hosts_file(common, resources, target_env)?;
+
+    let mut known: Vec<String> = match std::fs::read_to_string(&path) {
+        Ok(content) => content.lines().map(|l| l.to_string()).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+    };
+
+    let mut machines: Vec<&str> = Vec::new();
+    for (machine_name, _) in envdef.list_non_proxies() {
+        machines.push(machine_name);
+        machines.extend(envdef.jump_chain(machine_name)?.iter().map(|j| j.as_str()));
+    }
+    machines.sort_unstable();
+    machines.dedup();
+    let (direct, jumped): (Vec<&str>, Vec<&str>) = machines
+        .into_iter()
+        .partition(|m| envdef.jump_chain(m).is_ok_and(|chain| chain.is_empty()));
+
+    let limits = resources.jump_limits(target_env)?;
+    let mut added = 0;
+    let mut failures = 0;
+    for machines in [direct, jumped] {
+        let mut scans = Vec::new();
+        for machine_name in machines {
+            scans.push(FanoutTask {
+                machine_name,
+                jump: envdef.jump_chain(machine_name)?.last().map(|j| j.as_str()),
+                command: keyscan_command(common, resources, target_env, machine_name)?,
+            });
+        }
+        if scans.is_empty() {
+            continue;
+        }
+        if common.dry_run {
+            for scan in scans.iter() {
+                println!("{}", command_line(&scan.command));
+            }
+            continue;
+        }
 
-    if let Some(target_env) = target_env {
-        let envdef = resources.get_target_env(*target_env)?;
-        for k in envdef.list_non_proxies().map(|(k, _)| k) {
-            println!("{}", k)
+        let mut new_lines: Vec<String> = Vec::new();
+        for (machine_name, result) in fanout(scans, jobs, &limits, keyscan_result) {
+            let keys = match result {
+                Ok((keys, _)) => keys,
+                Err(e) => {
+                    eprintln!("cannot scan {}: {}", machine_name, e);
+                    failures += 1;
+                    continue;
+                }
+            };
+            let lines: Vec<String> = keys
+                .lines()
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .filter(|l| !known.iter().chain(new_lines.iter()).any(|k| k == l))
+                .map(|l| l.to_string())
+                .collect();
+            if !lines.is_empty() {
+                if common.verbose > 0 {
+                    println!("adding {} key(s) of {}", lines.len(), machine_name);
+                }
+                added += 1;
+                new_lines.extend(lines);
+            }
+        }
+
+        if !new_lines.is_empty() {
+            known.extend(new_lines);
+            let mut content = known.join("\n");
+            content.push('\n');
+            std::fs::write(&path, content)
+                .with_context(|| format!("cannot write {}", path.display()))?;
+        }
+    }
+
+    if !common.dry_run {
+        println!("{} host(s) added to {}", added, path.display());
+    }
+    if failures > 0 {
+        bail!("{} machines could not be scanned", failures)
+    }
+    Ok(())
+}
+
+/// Compare the machines of two environments, and their fields
+fn diff(resources: &Resource, env_a: &str, env_b: &str) -> Result<()> {
+    let machines_a = &resources.get_target_env(env_a)?.0;
+    let machines_b = &resources.get_target_env(env_b)?.0;
+
+    let mut names: Vec<&str> = sorted_keys(machines_a);
+    names.extend(sorted_keys(machines_b));
+    names.sort_unstable();
+    names.dedup();
+
+    let mut differences = 0;
+    for name in names {
+        let (a, b) = match (machines_a.get(name), machines_b.get(name)) {
+            (Some(_), None) => {
+                println!("- {} (only in {})", name, env_a);
+                differences += 1;
+                continue;
+            }
+            (None, Some(_)) => {
+                println!("+ {} (only in {})", name, env_b);
+                differences += 1;
+                continue;
+            }
+            (Some(a), Some(b)) => (toml::Value::try_from(a)?, toml::Value::try_from(b)?),
+            (None, None) => unreachable!(),
+        };
+        let (a, b) = match (a, b) {
+            (toml::Value::Table(a), toml::Value::Table(b)) => (a, b),
+            _ => continue,
+        };
+
+        let mut fields: Vec<&String> = a.keys().chain(b.keys()).collect();
+        fields.sort_unstable();
+        fields.dedup();
+        for field in fields {
+            let show = |v: Option<&toml::Value>| match v {
+                None => "(unset)".to_string(),
+                Some(v) => v.to_string(),
+            };
+            let (va, vb) = (a.get(field), b.get(field));
+            if va != vb {
+                println!("~ {}.{}: {} -> {}", name, field, show(va), show(vb));
+                differences += 1;
+            }
+        }
+    }
+    if differences == 0 {
+        println!("no differences between {} and {}", env_a, env_b);
+    }
+    Ok(())
+}
+
+/// Print the value at the dotted path (e.g. `server.prod.web1.ip`) of the resources
+fn query(resources: &Resource, path: &str) -> Result<()> {
+    let root = toml::Value::try_from(resources)?;
+
+    let mut value = &root;
+    for key in path.split('.').filter(|k| !k.is_empty()) {
+        value = value
+            .get(key)
+            .ok_or_else(|| anyhow!("path {} doesn't exist (no {})", path, key))?;
+    }
+
+    match value {
+        toml::Value::String(s) => println!("{}", s),
+        toml::Value::Table(_) => print!("{}", toml::to_string(value)?),
+        v => println!("{}", v),
+    }
+    Ok(())
+}
+
+/// Print a Procfile with one tunnel process per resource of the environment
+fn export_tunnels(common: &CommonArgs, resources: &Resource, target_env: &str) -> Result<()> {
+    let defs = resources.get_target_env_resources(target_env)?;
+    let names = sorted_keys(&defs.0);
+
+    for resource_name in names {
+        let def = defs.get_resource(resource_name)?;
+        let ssh_opt = ssh_login(common, resources, target_env, &def.server)?;
+        let forwards = [(def, def.default_local_port())];
+        let command = tunnel_command(common, ssh_opt, &forwards, &TunnelOpts::default())?;
+        println!("{}: {}", resource_name, command_line(&command));
+    }
+    Ok(())
+}
+
+/// Render the connection to a machine as an ssh_config Host block
+///
+/// An ssh config file given with `-F` is included at the end of the block, so
+/// that the options set by machlist keep taking precedence over its ones.
+fn ssh_config_host(common: &CommonArgs, alias: &str, ssh_opt: &Ssh) -> Result<String> {
+    let mut lines = vec![format!("Host {}", alias)];
+    match ssh_opt.dest.split_once('@') {
+        Some((user, host)) => {
+            lines.push(format!("    HostName {}", host));
+            lines.push(format!("    User {}", user));
+        }
+        None => lines.push(format!("    HostName {}", ssh_opt.dest)),
+    }
+
+    if common.compress {
+        lines.push("    Compression yes".to_string());
+    }
+    let mut include = None;
+    let mut args = ssh_opt.args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("ssh option {} is missing its value", arg))
+        };
+        match arg.as_str() {
+            "-J" => lines.push(format!("    ProxyJump {}", value()?)),
+            "-p" => lines.push(format!("    Port {}", value()?)),
+            "-i" => lines.push(format!("    IdentityFile {}", value()?)),
+            "-F" => include = Some(value()?),
+            "-A" => lines.push("    ForwardAgent yes".to_string()),
+            "-C" => lines.push("    Compression yes".to_string()),
+            _ => match arg.strip_prefix("-o").and_then(|o| o.split_once('=')) {
+                Some((key, value)) => lines.push(format!("    {} {}", key, value)),
+                None => bail!("cannot convert the ssh option {} to ssh_config", arg),
+            },
+        }
+    }
+    if let Some(include) = include {
+        lines.push(format!("    Include {}", include));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Print how a machine is reached: host, user, port and jump
+///
+/// The `dotenv` format is `MACHLIST_*=value` lines, for `.env` consumers
+/// (e.g. docker-compose) or to be sourced by a shell.
+fn resolve(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+    machine_name: &str,
+    format: &str,
+) -> Result<()> {
+    let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+
+    let (user, host) = match ssh_opt.dest.split_once('@') {
+        Some((user, host)) => (user, host),
+        None => ("", ssh_opt.dest.as_str()),
+    };
+    let mut port = DEFAULT_SSH_PORT.to_string();
+    let mut jump = "";
+    let mut args = ssh_opt.args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-J" {
+            jump = args.next().map(|j| j.as_str()).unwrap_or("");
+        } else if arg == "-p" {
+            port = args.next().cloned().unwrap_or(port);
+        }
+    }
+
+    let fields = [
+        ("HOST", host),
+        ("USER", user),
+        ("PORT", &port),
+        ("JUMP", jump),
+    ];
+    for (key, value) in fields {
+        match format {
+            "dotenv" => println!("MACHLIST_{}={}", key, value),
+            _ => println!("{}: {}", key.to_lowercase(), value),
+        }
+    }
+    Ok(())
+}
+
+/// Print the ssh_config Host blocks of the machines of an environment
+///
+/// The hosts are named `<env>.<machine>`, and the output is meant to be written
+/// to a file included from the main ssh config (e.g. `Include ~/.machlist/ssh/*`)
+fn export_ssh_include(common: &CommonArgs, resources: &Resource, target_env: &str) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+
+    println!("# generated by machlist for environment {}", target_env);
+    for machine_name in sorted_keys(&envdef.0) {
+        let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+        let alias = format!("{}.{}", target_env, machine_name);
+        println!();
+        println!("{}", ssh_config_host(common, &alias, &ssh_opt)?);
+    }
+    Ok(())
+}
+
+/// Print an Ansible inventory (INI format) of the machines of an environment
+///
+/// The machines are grouped by tag, and the connection details (address,
+/// user, jump and other ssh options) are set as host variables.
+fn export_ansible_inventory(
+    common: &CommonArgs,
+    resources: &Resource,
+    target_env: &str,
+) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let mut machines: Vec<(&String, &ServerDef)> = envdef.list_non_proxies().collect();
+    machines.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut groups: std::collections::BTreeMap<&str, Vec<&str>> = Default::default();
+    println!("# generated by machlist for environment {}", target_env);
+    for (machine_name, machine_def) in machines.iter() {
+        let ssh_opt = ssh_login(common, resources, target_env, machine_name)?;
+        let mut line = machine_name.to_string();
+        match ssh_opt.dest.split_once('@') {
+            Some((user, host)) => {
+                line.push_str(&format!(" ansible_host={} ansible_user={}", host, user))
+            }
+            None => line.push_str(&format!(" ansible_host={}", ssh_opt.dest)),
+        }
+        let common_args: Vec<String> = ssh_opt
+            .args
+            .iter()
+            .map(|a| {
+                if a.contains(' ') {
+                    format!("\"{}\"", a)
+                } else {
+                    a.clone()
+                }
+            })
+            .collect();
+        line.push_str(&format!(
+            " ansible_ssh_common_args='{}'",
+            common_args.join(" ")
+        ));
+        println!("{}", line);
+
+        for tag in machine_def.tags.iter() {
+            groups
+                .entry(tag.as_str())
+                .or_default()
+                .push(machine_name.as_str());
+        }
+    }
+
+    for (group, members) in groups {
+        println!();
+        println!("[{}]", group);
+        for member in members {
+            println!("{}", member);
+        }
+    }
+    Ok(())
+}
+
+/// Print the resources of an environment, with a preview of their forwarding
+fn list_resources(
+    resources: &Resource,
+    target_env: &str,
+    filter: &dyn Fn(&str) -> bool,
+) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let defs = resources.get_target_env_resources(target_env)?;
+    let names = sorted_keys(&defs.0);
+
+    for name in names.into_iter().filter(|name| filter(name)) {
+        let def = defs.get_resource(name)?;
+        let jumps = envdef.jump_chain(&def.server)?;
+        let via = if jumps.is_empty() {
+            def.server.clone()
+        } else {
+            let jumps: Vec<&str> = jumps.iter().map(|j| j.as_str()).collect();
+            format!("{} (jump {})", def.server, jumps.join(","))
+        };
+        println!(
+            "{}: {} via {}",
+            name,
+            def.forwarding(def.default_local_port())?,
+            via
+        );
+    }
+    Ok(())
+}
+
+/// Print the resources with their machine and address, grouped by environment
+/// when none is given
+fn show_resources(resources: &Resource, target_env: Option<&str>) -> Result<()> {
+    let print = |target_env: &str| -> Result<()> {
+        let defs = resources.get_target_env_resources(target_env)?;
+        for name in sorted_keys(&defs.0) {
+            let def = &defs.0[name];
+            println!(
+                "{} -> server={} at={}:{}",
+                name,
+                def.server,
+                def.at()?,
+                def.port
+            );
+        }
+        Ok(())
+    };
+    match target_env {
+        Some(target_env) => print(target_env),
+        None => {
+            for target_env in sorted_keys(&resources.resource) {
+                println!("[{}]", target_env);
+                print(target_env)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Options of the list subcommand
+#[derive(Clone, Debug, Default)]
+struct ListOpts<'a> {
+    os: Option<&'a str>,
+    group: Option<&'a str>,
+    resources: bool,
+    json: bool,
+    /// Glob the listed names have to match, ignoring case
+    pattern: Option<&'a str>,
+    /// Print the number of machines and proxies of the environments instead
+    summary: bool,
+}
+
+/// Number of machines and proxies of an environment, e.g. `prod: 12 machines, 2 proxies`
+fn list_summary(resources: &Resource, target_env: &str) -> Result<()> {
+    let envdef = resources.get_target_env(target_env)?;
+    let machines = envdef.list_non_proxies().count();
+    let proxies = envdef.list_proxies().count();
+    println!(
+        "{}: {} machine{}, {} prox{}",
+        target_env,
+        machines,
+        if machines == 1 { "" } else { "s" },
+        proxies,
+        if proxies == 1 { "y" } else { "ies" }
+    );
+    Ok(())
+}
+
+/// Machine in the JSON output of `list`
+#[derive(Serialize)]
+struct MachineJson<'a> {
+    name: &'a str,
+    ip: Option<&'a str>,
+    proxy: bool,
+}
+
+/// Environments in the JSON output of `list`
+#[derive(Serialize)]
+struct EnvironmentsJson<'a> {
+    environments: Vec<&'a str>,
+}
+
+/// The machines as a JSON array, for `list --json`
+fn machines_json(machines: &[(&String, &ServerDef)]) -> Result<String> {
+    let entries: Vec<MachineJson> = machines
+        .iter()
+        .map(|(name, def)| MachineJson {
+            name,
+            ip: def.address(None).map(|ip| ip.as_str()),
+            proxy: def.proxy.unwrap_or(false),
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&entries)?)
+}
+
+fn list(resources: &Resource, target_env: &Option<&str>, opts: &ListOpts) -> Result<()> {
+    let pattern = opts
+        .pattern
+        .map(|glob| Pattern::glob(&glob.to_lowercase()))
+        .transpose()?;
+    let filter = |name: &str| {
+        pattern
+            .as_ref()
+            .map(|p| p.is_match(&name.to_lowercase()))
+            .unwrap_or(true)
+    };
+    if opts.summary {
+        match target_env {
+            Some(target_env) => list_summary(resources, target_env)?,
+            None => {
+                for target_env in sorted_keys(&resources.server) {
+                    if filter(target_env) {
+                        list_summary(resources, target_env)?;
+                    }
+                }
+            }
         }
+    } else if opts.resources {
+        match target_env {
+            Some(target_env) => list_resources(resources, target_env, &filter)?,
+            None => {
+                for target_env in sorted_keys(&resources.resource) {
+                    if filter(target_env) {
+                        println!("[{}]", target_env);
+                        list_resources(resources, target_env, &|_| true)?;
+                    }
+                }
+            }
+        }
+    } else if let Some(target_env) = target_env {
+        let envdef = resources.get_target_env(target_env)?;
+        let group = opts.group.map(|g| resources.get_group(g)).transpose()?;
+        let mut machines: Vec<(&String, &ServerDef)> = envdef
+            .list_non_proxies()
+            .filter(|(_, v)| opts.os.map(|os| v.is_os(os)).unwrap_or(true))
+            .filter(|(_, v)| group.as_ref().map(|g| g.eval(&v.tags)).unwrap_or(true))
+            .filter(|(k, _)| filter(k))
+            .collect();
+        machines.sort_by(|a, b| a.0.cmp(b.0));
+        if opts.json {
+            println!("{}", machines_json(&machines)?);
+            return Ok(());
+        }
+        let dim = std::io::stdout().is_terminal();
+        for (k, v) in machines {
+            let line = match &v.os {
+                None => k.to_string(),
+                Some(os) => format!("{} ({})", k, os),
+            };
+            if !v.is_deprecated() {
+                println!("{}", line)
+            } else if dim {
+                println!("\x1b[2m{} [deprecated]\x1b[0m", line)
+            } else {
+                println!("{} [deprecated]", line)
+            }
+        }
+    } else if opts.json {
+        let environments = EnvironmentsJson {
+            environments: sorted_keys(&resources.server)
+                .into_iter()
+                .filter(|k| filter(k))
+                .collect(),
+        };
+        println!("{}", serde_json::to_string_pretty(&environments)?);
     } else {
         println!("listing all target environments");
-        for k in resources.server.keys() {
+        for k in resources.server.keys().filter(|k| filter(k)) {
             println!("{}", k)
         }
     }
     Ok(())
 }
 
+/// Ask to pick one of the choices on the terminal
+fn pick<'a>(prompt: &str, choices: &[&'a str]) -> Result<&'a str> {
+    use std::io::{BufRead, Write};
+    if choices.is_empty() {
+        bail!("nothing to choose for {}", prompt)
+    }
+    loop {
+        println!("{}:", prompt);
+        for (i, choice) in choices.iter().enumerate() {
+            println!("  {}) {}", i + 1, choice);
+        }
+        print!("> ");
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if std::io::stdin().lock().read_line(&mut line)? == 0 {
+            bail!("no choice made")
+        }
+        match line.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= choices.len() => return Ok(choices[n - 1]),
+            _ => println!("invalid choice {}", line.trim()),
+        }
+    }
+}
+
+fn sorted_keys<V>(map: &HashMap<String, V>) -> Vec<&str> {
+    let mut keys: Vec<&str> = map.keys().map(|k| k.as_str()).collect();
+    keys.sort_unstable();
+    keys
+}
+
+/// Guided selection of the command, the environment and the machine or resource
+fn interactive(common: &CommonArgs, resources: &Resource) -> Result<()> {
+    match pick("command", &["shell", "tunnel"])? {
+        "shell" => {
+            let target_env = pick("environment", &sorted_keys(&resources.server))?;
+            let envdef = resources.get_target_env(target_env)?;
+            let mut machines: Vec<&str> =
+                envdef.list_non_proxies().map(|(k, _)| k.as_str()).collect();
+            machines.sort_unstable();
+            let machine = pick("machine", &machines)?;
+            shell(
+                common,
+                resources,
+                target_env,
+                machine,
+                &ShellOpts::default(),
+            )
+        }
+        _ => {
+            let target_env = pick("environment", &sorted_keys(&resources.resource))?;
+            let defs = resources.get_target_env_resources(target_env)?;
+            let resource = pick("resource", &sorted_keys(&defs.0))?;
+            tunnel(
+                common,
+                resources,
+                target_env,
+                &[resource],
+                &TunnelOpts::default(),
+            )
+        }
+    }
+}
+
+#[derive(Default)]
 struct CommonArgs {
     verbose: u64,
+    compress: bool,
+    keepalive: Option<u32>,
+    known_hosts_mode: Option<String>,
+    no_webhook: bool,
+    trace: Option<PathBuf>,
+    /// File the connections are appended to
+    log: Option<PathBuf>,
+    prefer_family: Option<AddressFamily>,
+    netns: Option<String>,
+    /// ssh private key, unless the machine defines its own
+    identity: Option<String>,
+    /// Username overriding the configured one
+    user: Option<String>,
+    /// Machine jumped through instead of the configured jump hosts
+    jump: Option<String>,
+    /// `KEY=VALUE` ssh options given on the command line
+    ssh_options: Vec<String>,
+    known_hosts_dir: Option<String>,
+    /// Don't print the status lines
+    quiet: bool,
+    /// Print the commands instead of running them
+    dry_run: bool,
     res_file: PathBuf,
 }
 
-fn main() -> Result<()> {
-    const ARG_VERBOSE: &str = "verbose";
-    const ARG_RES_FILE: &str = "res-file";
+impl CommonArgs {
+    /// Fill the values not specified on the command line with the configuration defaults
+    pub fn with_defaults(self, defaults: &Defaults) -> Self {
+        CommonArgs {
+            verbose: if self.verbose > 0 {
+                self.verbose
+            } else {
+                defaults.verbose.unwrap_or(0)
+            },
+            compress: self.compress || defaults.compress.unwrap_or(false),
+            keepalive: self.keepalive.or(defaults.keepalive),
+            known_hosts_mode: self
+                .known_hosts_mode
+                .or_else(|| defaults.known_hosts_mode.clone()),
+            prefer_family: self.prefer_family.or(match defaults.prefer_ipv6 {
+                Some(true) => Some(AddressFamily::Inet6),
+                Some(false) => Some(AddressFamily::Inet),
+                None => None,
+            }),
+            ..self
+        }
+    }
+
+    /// Print a status line (e.g. where we connect to) on stderr, unless quiet
+    pub fn status(&self, message: std::fmt::Arguments) {
+        if !self.quiet {
+            eprintln!("{}", message);
+        }
+    }
+
+    /// ssh options common to all the ssh based commands (ssh, scp, rsync transport)
+    pub fn transport_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.verbose > 0 {
+            args.push("-v".to_string());
+        }
+        if self.compress {
+            args.push("-C".to_string());
+        }
+        if let Some(keepalive) = self.keepalive {
+            args.push(format!("-oServerAliveInterval={}", keepalive));
+        }
+        if let Some(mode) = &self.known_hosts_mode {
+            args.push(format!("-oStrictHostKeyChecking={}", mode));
+        }
+        args
+    }
+}
+
+const ARG_VERBOSE: &str = "verbose";
+const ARG_RES_FILE: &str = "res-file";
+const ARG_COMPRESS: &str = "compress";
+const ARG_KEEPALIVE: &str = "keepalive";
+const ARG_KNOWN_HOSTS_MODE: &str = "known-hosts-mode";
+const ARG_NO_WEBHOOK: &str = "no-webhook";
+const ARG_PREFER_IPV4: &str = "prefer-ipv4";
+const ARG_PREFER_IPV6: &str = "prefer-ipv6";
+const ARG_TRACE: &str = "trace";
+const ARG_LOG: &str = "log";
+const ARG_NETNS: &str = "netns";
+const ARG_IDENTITY: &str = "identity";
+const ARG_SSH_OPTION: &str = "ssh-option";
+const ARG_KNOWN_HOSTS_DIR: &str = "known-hosts-dir";
+const ARG_USER: &str = "user";
+const ARG_JUMP: &str = "jump";
+const ARG_QUIET: &str = "quiet";
+const ARG_DRY_RUN: &str = "dry-run";
+const ARG_ENV_FILE: &str = "env-file";
+
+const SUBCMD_SHELL: &str = "shell";
+const ARG_TARGET_ENV: &str = "target-env";
+const ARG_MACHINE: &str = "machine";
+const ARG_SHELL_STOP_AT: &str = "stop-at";
+const ARG_SHELL_VERIFY_IDENTITY: &str = "verify-identity";
+const ARG_SHELL_MOSH: &str = "mosh";
+const ARG_SHELL_ADHOC: &str = "adhoc";
+const ARG_SHELL_RECORD: &str = "record";
+const ARG_EXPLAIN: &str = "explain";
+
+const SUBCMD_EXEC: &str = "exec";
+const ARG_EXEC_COMMAND: &str = "command";
+const ARG_EXEC_ALL: &str = "all";
+const ARG_EXEC_OS: &str = "os";
+const ARG_EXEC_JOBS: &str = "jobs";
+
+const SUBCMD_EXPORT: &str = "export";
+const SUBCMD_EXPORT_TUNNELS: &str = "tunnels";
+const SUBCMD_EXPORT_SSH_INCLUDE: &str = "ssh-include";
+const SUBCMD_EXPORT_ANSIBLE_INVENTORY: &str = "ansible-inventory";
+
+const SUBCMD_CONFIG: &str = "config";
+const SUBCMD_CONFIG_CHECK: &str = "check";
+const SUBCMD_CONFIG_EDIT: &str = "edit";
 
-    const SUBCMD_SHELL: &str = "shell";
-    const ARG_TARGET_ENV: &str = "target-env";
-    const ARG_MACHINE: &str = "machine";
+const SUBCMD_QUERY: &str = "query";
+const SUBCMD_DIFF: &str = "diff";
+const SUBCMD_OTP: &str = "otp";
+const SUBCMD_PING: &str = "ping";
+const SUBCMD_RESOLVE: &str = "resolve";
+const ARG_RESOLVE_FORMAT: &str = "format";
+const SUBCMD_TRUST: &str = "trust";
+const SUBCMD_KNOWN_HOSTS: &str = "known-hosts";
+const SUBCMD_KNOWN_HOSTS_SYNC: &str = "sync";
+const SUBCMD_KNOWN_HOSTS_PATH: &str = "path";
+const ARG_TRUST_ALL: &str = "all";
+const ARG_TRUST_APPEND: &str = "append";
+const ARG_TRUST_JOBS: &str = "jobs";
+const ARG_DIFF_ENV_A: &str = "diff-env-a";
+const ARG_DIFF_ENV_B: &str = "diff-env-b";
+const ARG_QUERY_PATH: &str = "query-path";
 
-    const SUBCMD_LIST: &str = "list";
+const SUBCMD_LIST: &str = "list";
+const SUBCMD_RESOURCES: &str = "resources";
+const ARG_LIST_OS: &str = "os";
+const ARG_LIST_RESOURCES: &str = "resources";
+const ARG_LIST_GROUP: &str = "group";
+const ARG_LIST_FORMAT: &str = "format";
+const ARG_LIST_PATTERN: &str = "pattern";
+const ARG_LIST_SUMMARY: &str = "summary";
 
-    const SUBCMD_COPY_FROM: &str = "copy-from";
-    const ARG_COPY_FROM_PATH: &str = "copy-from-path";
+const SUBCMD_COPY_FROM: &str = "copy-from";
+const ARG_COPY_FROM_PATH: &str = "copy-from-path";
+const ARG_COPY_FROM_GLOB: &str = "glob";
+const ARG_COPY_FROM_RECURSIVE: &str = "recursive";
+const ARG_COPY_FROM_DEST: &str = "dest";
 
-    const SUBCMD_COPY_TO: &str = "copy-to";
-    const ARG_COPY_TO_PATH: &str = "copy-to-path";
+const SUBCMD_COPY_TO: &str = "copy-to";
+const ARG_COPY_TO_PATH: &str = "copy-to-path";
 
-    const SUBCMD_TUNNEL: &str = "tunnel";
-    const ARG_TUNNEL_RESOURCE: &str = "tunnel-resource";
-    const ARG_TUNNEL_LOCAL_PORT: &str = "tunnel-local-port";
+const SUBCMD_COPY_BETWEEN: &str = "copy-between";
+const ARG_COPY_BETWEEN_SRC: &str = "copy-between-src";
+const ARG_COPY_BETWEEN_DST: &str = "copy-between-dst";
 
-    let default_machlist_file = machlist_local().display().to_string();
+const ARG_COPY_RESUME: &str = "resume";
+
+const SUBCMD_TUNNEL: &str = "tunnel";
+const ARG_TUNNEL_RESOURCE: &str = "tunnel-resource";
+const ARG_TUNNEL_GROUP: &str = "group";
+const ARG_TUNNEL_LOCAL_PORT: &str = "tunnel-local-port";
+const ARG_TUNNEL_FAMILY: &str = "tunnel-family";
+const ARG_TUNNEL_WATCH_CONFIG: &str = "watch-config";
+const ARG_TUNNEL_CHECK: &str = "check";
+const ARG_TUNNEL_HOSTS_ALIAS: &str = "hosts-alias";
+const ARG_TUNNEL_RECONNECT: &str = "reconnect";
+const ARG_TUNNEL_CONNECT: &str = "connect";
+const ARG_TUNNEL_REVERSE: &str = "reverse";
+const ARG_TUNNEL_BIND_ADDRESS: &str = "bind-address";
+const ARG_TUNNEL_BACKGROUND: &str = "background";
+const SUBCMD_COMPLETION: &str = "completion";
+const ARG_COMPLETION_SHELL: &str = "shell";
+const SUBCMD_COMPLETE: &str = "_complete";
+const ARG_COMPLETE_PREFIX: &str = "prefix";
+
+/// Print the names starting with a prefix, one per line: the machines of the
+/// environment if given, the environments otherwise
+///
+/// This is called back by completion scripts, so a missing or invalid
+/// resources file completes nothing rather than printing errors
+fn complete(res_file: Option<&str>, target_env: Option<&str>, prefix: &str) {
+    let resources =
+        match resolve_resource_file(res_file).and_then(|file| Ok(parse_resources(file)?)) {
+            Ok(resources) => resources,
+            Err(_) => return,
+        };
+    let mut names: Vec<&str> = match target_env {
+        Some(target_env) => match resources.server.get(target_env) {
+            Some(envdef) => envdef.list_non_proxies().map(|(k, _)| k.as_str()).collect(),
+            None => Vec::new(),
+        },
+        None => sorted_keys(&resources.server),
+    };
+    names.retain(|name| name.starts_with(prefix));
+    names.sort_unstable();
+    for name in names {
+        println!("{}", name);
+    }
+}
+
+/// Command line definition, shared by the parsing and the completion scripts
+fn build_cli() -> App<'static, 'static> {
+    let arg_res_file = Arg::with_name(ARG_RES_FILE)
+        .help("TOML Resource file to use (default to the first found of ./machlist-resources.toml, .machlist/resources.toml of the project, ~/.config/machlist/resources.toml and ~/.machlist/resources.toml)")
+        .global(true)
+        .multiple(false)
+        .takes_value(true)
+        .short("r");
 
     let arg_target_env = Arg::with_name(ARG_TARGET_ENV)
         .help("Target environment (alpha, prod, ..)")
         .takes_value(true)
         .short("t")
         .long("target");
+    let arg_copy_resume = Arg::with_name(ARG_COPY_RESUME)
+        .help("Resume interrupted transfer using rsync (require rsync on both ends)")
+        .long("resume");
+    let arg_explain = Arg::with_name(ARG_EXPLAIN)
+        .help("Print how the connection is resolved (jump hosts, destination, ssh command) without connecting")
+        .long("explain");
     let arg_machine = Arg::with_name(ARG_MACHINE)
         .help("machine destination")
         .required(true);
 
-    let app = App::new("machlist")
+    App::new("machlist")
         .arg(
             Arg::with_name(ARG_VERBOSE)
                 .global(true)
@@ -373,20 +4586,212 @@ fn main() -> Result<()> {
                 .multiple(true)
                 .short("v"),
         )
+        .arg(arg_res_file)
+        .arg(
+            Arg::with_name(ARG_COMPRESS)
+                .help("Enable compression of the ssh connection")
+                .global(true)
+                .short("C")
+                .long("compress"),
+        )
+        .arg(
+            Arg::with_name(ARG_KEEPALIVE)
+                .help("Interval in seconds of the keepalive messages sent to the server")
+                .global(true)
+                .takes_value(true)
+                .long("keepalive"),
+        )
+        .arg(
+            Arg::with_name(ARG_KNOWN_HOSTS_MODE)
+                .help("Host key checking mode (yes, no, accept-new, ask)")
+                .global(true)
+                .takes_value(true)
+                .long("known-hosts-mode"),
+        )
+        .arg(
+            Arg::with_name(ARG_NO_WEBHOOK)
+                .help("Do not notify the configured webhook of the connection")
+                .global(true)
+                .long("no-webhook"),
+        )
+        .arg(
+            Arg::with_name(ARG_PREFER_IPV4)
+                .help("Connect to the IPv4 address of dual-stack machines, if any")
+                .global(true)
+                .long("prefer-ipv4")
+                .conflicts_with(ARG_PREFER_IPV6),
+        )
+        .arg(
+            Arg::with_name(ARG_PREFER_IPV6)
+                .help("Connect to the IPv6 address of dual-stack machines, if any")
+                .global(true)
+                .long("prefer-ipv6"),
+        )
+        .arg(
+            Arg::with_name(ARG_TRACE)
+                .help("Write the resolution of the connection as JSON to this file")
+                .global(true)
+                .takes_value(true)
+                .long("trace"),
+        )
+        .arg(
+            Arg::with_name(ARG_LOG)
+                .help("Append a line to this file (possibly env:VAR) for each connection")
+                .global(true)
+                .takes_value(true)
+                .value_name("PATH")
+                .long("log"),
+        )
+        .arg(
+            Arg::with_name(ARG_NETNS)
+                .help("Network namespace to connect from (with ip netns exec, as root)")
+                .global(true)
+                .takes_value(true)
+                .long("netns"),
+        )
+        .arg(
+            Arg::with_name(ARG_IDENTITY)
+                .help("ssh private key to connect with (unless the machine defines its `identity`)")
+                .global(true)
+                .takes_value(true)
+                .short("i")
+                .long("identity"),
+        )
+        .arg(
+            Arg::with_name(ARG_USER)
+                .help("Username to connect as, instead of the configured one")
+                .global(true)
+                .takes_value(true)
+                .short("u")
+                .long("user"),
+        )
+        .arg(
+            Arg::with_name(ARG_JUMP)
+                .help("Machine of the environment to jump through, instead of the configured jump hosts")
+                .global(true)
+                .takes_value(true)
+                .value_name("MACHINE")
+                .long("jump"),
+        )
+        .arg(
+            Arg::with_name(ARG_KNOWN_HOSTS_DIR)
+                .help("Directory of the known hosts files of the environments (default to ~/.ssh)")
+                .global(true)
+                .takes_value(true)
+                .long("known-hosts-dir"),
+        )
         .arg(
-            Arg::with_name(ARG_RES_FILE)
-                .help("TOML Resource file to use")
-                .default_value(default_machlist_file.as_str())
+            Arg::with_name(ARG_SSH_OPTION)
+                .help("ssh option KEY=VALUE, taking precedence over the ones of machlist (can be repeated)")
                 .global(true)
-                .multiple(false)
                 .takes_value(true)
-                .short("r"),
+                .multiple(true)
+                .number_of_values(1)
+                .short("o")
+                .long("ssh-option"),
+        )
+        .arg(
+            Arg::with_name(ARG_QUIET)
+                .help("Don't print the status lines (e.g. connecting ..), even with -v")
+                .global(true)
+                .short("q")
+                .long("quiet"),
+        )
+        .arg(
+            Arg::with_name(ARG_ENV_FILE)
+                .help("Load the KEY=VALUE lines of a file (e.g. .env) into the environment, before resolving the env: values")
+                .global(true)
+                .takes_value(true)
+                .value_name("PATH")
+                .long("env-file"),
+        )
+        .arg(
+            Arg::with_name(ARG_DRY_RUN)
+                .help("Print the commands that would be run, instead of running them")
+                .global(true)
+                .short("n")
+                .long("dry-run"),
         )
         .subcommand(
             SubCommand::with_name(SUBCMD_SHELL)
                 .about("Shell on a given resource")
                 .arg(&arg_target_env)
-                .arg(&arg_machine),
+                .arg(arg_machine.clone().required_unless(ARG_SHELL_ADHOC))
+                .arg(
+                    Arg::with_name(ARG_SHELL_STOP_AT)
+                        .help("Connect to this jump host of the chain instead of the machine")
+                        .takes_value(true)
+                        .long("stop-at"),
+                )
+                .arg(
+                    Arg::with_name(ARG_SHELL_VERIFY_IDENTITY)
+                        .help("Check the machine's hostname against its connect_banner_check")
+                        .long("verify-identity"),
+                )
+                .arg(
+                    Arg::with_name(ARG_SHELL_RECORD)
+                        .help("Record the session to this file (with asciinema, or script)")
+                        .takes_value(true)
+                        .long("record"),
+                )
+                .arg(
+                    Arg::with_name(ARG_SHELL_MOSH)
+                        .help("Connect with mosh instead of the machine's backend")
+                        .long("mosh"),
+                )
+                .arg(
+                    Arg::with_name(ARG_SHELL_ADHOC)
+                        .help("Connect to this host[:port], not in the resources file, with the known hosts of the environment")
+                        .takes_value(true)
+                        .value_name("HOST[:PORT]")
+                        .long("adhoc")
+                        .conflicts_with_all(&[
+                            ARG_MACHINE,
+                            ARG_SHELL_STOP_AT,
+                            ARG_SHELL_VERIFY_IDENTITY,
+                            ARG_SHELL_MOSH,
+                            ARG_EXPLAIN,
+                        ]),
+                )
+                .arg(&arg_explain),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_EXEC)
+                .about("Run a command on a given machine, or all of them with --all (e.g. exec web -- uptime)")
+                .arg(&arg_target_env)
+                .arg(
+                    Arg::with_name(ARG_MACHINE)
+                        .help("machine destination")
+                        .required_unless(ARG_EXEC_ALL),
+                )
+                .arg(
+                    Arg::with_name(ARG_EXEC_ALL)
+                        .help("Run the command on all the machines of the environment")
+                        .long("all")
+                        .conflicts_with(ARG_MACHINE),
+                )
+                .arg(
+                    Arg::with_name(ARG_EXEC_OS)
+                        .help("Only run on the machines declaring this operating system")
+                        .takes_value(true)
+                        .long("os")
+                        .requires(ARG_EXEC_ALL),
+                )
+                .arg(
+                    Arg::with_name(ARG_EXEC_JOBS)
+                        .help("Number of machines the command runs on at once")
+                        .takes_value(true)
+                        .long("jobs")
+                        .short("j")
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::with_name(ARG_EXEC_COMMAND)
+                        .help("Command to run, after --")
+                        .multiple(true)
+                        .required(true)
+                        .last(true),
+                ),
         )
         .subcommand(
             SubCommand::with_name(SUBCMD_COPY_FROM)
@@ -395,8 +4800,27 @@ fn main() -> Result<()> {
                 .arg(&arg_machine)
                 .arg(
                     Arg::with_name(ARG_COPY_FROM_PATH)
-                        .help("Path to copy")
+                        .help("Paths to copy")
+                        .multiple(true)
                         .required(true),
+                )
+                .arg(&arg_copy_resume)
+                .arg(
+                    Arg::with_name(ARG_COPY_FROM_GLOB)
+                        .help("Let the remote shell expand the path (e.g. /var/log/*.log)")
+                        .long("glob"),
+                )
+                .arg(
+                    Arg::with_name(ARG_COPY_FROM_RECURSIVE)
+                        .help("Copy directories recursively")
+                        .short("R")
+                        .long("recursive"),
+                )
+                .arg(
+                    Arg::with_name(ARG_COPY_FROM_DEST)
+                        .help("Local directory to copy to (default to the current one)")
+                        .takes_value(true)
+                        .long("dest"),
                 ),
         )
         .subcommand(
@@ -408,6 +4832,22 @@ fn main() -> Result<()> {
                     Arg::with_name(ARG_COPY_TO_PATH)
                         .help("Path to copy")
                         .required(true),
+                )
+                .arg(&arg_copy_resume),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_COPY_BETWEEN)
+                .about("Copy a file from a machine to another, through the local host")
+                .arg(&arg_target_env)
+                .arg(
+                    Arg::with_name(ARG_COPY_BETWEEN_SRC)
+                        .help("Source, as <machine>:<path>")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(ARG_COPY_BETWEEN_DST)
+                        .help("Destination, as <machine>:<path>")
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -416,54 +4856,1278 @@ fn main() -> Result<()> {
                 .arg(&arg_target_env)
                 .arg(
                     Arg::with_name(ARG_TUNNEL_RESOURCE)
-                        .help("Resource on machine to open")
-                        .required(true),
+                        .help("Resources on machine to open, comma separated (e.g. db,redis)")
+                        .use_delimiter(true)
+                        .required_unless(ARG_TUNNEL_GROUP),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_GROUP)
+                        .help("Open the resources of this resource group")
+                        .takes_value(true)
+                        .conflicts_with(ARG_TUNNEL_RESOURCE)
+                        .long("group"),
                 )
                 .arg(
                     Arg::with_name(ARG_TUNNEL_LOCAL_PORT)
                         .help("port to bind (default to resource define)")
                         .required(false),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_FAMILY)
+                        .help("Force the address family used for the tunnel")
+                        .takes_value(true)
+                        .possible_values(&["4", "6"])
+                        .long("tunnel-family"),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_WATCH_CONFIG)
+                        .help("Re-establish the tunnel when the resources file changes its address")
+                        .long("watch-config"),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_CHECK)
+                        .help(
+                            "Check the tunnel can be established and print it, without connecting",
+                        )
+                        .long("check"),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_HOSTS_ALIAS)
+                        .help("Resolve the resource's host locally during the tunnel, by adding it to /etc/hosts (need root privileges)")
+                        .long("hosts-alias"),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_RECONNECT)
+                        .help("Re-establish the tunnel when it terminates, until interrupted")
+                        .long("reconnect")
+                        .alias("autossh"),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_CONNECT)
+                        .help("Run the resource's client command through the tunnel, closing the tunnel when it exits")
+                        .long("connect")
+                        .conflicts_with_all(&[ARG_TUNNEL_WATCH_CONFIG, ARG_TUNNEL_RECONNECT]),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_REVERSE)
+                        .help("Forward the resource's port on its machine to the local port (ssh -R)")
+                        .long("reverse")
+                        .conflicts_with_all(&[ARG_TUNNEL_CONNECT, ARG_TUNNEL_HOSTS_ALIAS]),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_BIND_ADDRESS)
+                        .help("Local address the tunnel listens on (e.g. 0.0.0.0 for all the interfaces)")
+                        .long("bind-address")
+                        .takes_value(true)
+                        .value_name("ADDR")
+                        .conflicts_with_all(&[ARG_TUNNEL_REVERSE, ARG_TUNNEL_HOSTS_ALIAS]),
+                )
+                .arg(
+                    Arg::with_name(ARG_TUNNEL_BACKGROUND)
+                        .help("Detach the tunnel from the terminal and print its PID")
+                        .long("background")
+                        .conflicts_with_all(&[
+                            ARG_TUNNEL_WATCH_CONFIG,
+                            ARG_TUNNEL_RECONNECT,
+                            ARG_TUNNEL_CONNECT,
+                            ARG_TUNNEL_HOSTS_ALIAS,
+                        ]),
+                )
+                .arg(&arg_explain),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_EXPORT)
+                .about("Export configuration for other tools")
+                .subcommand(
+                    SubCommand::with_name(SUBCMD_EXPORT_TUNNELS)
+                        .about("Export the tunnels of an environment as a Procfile")
+                        .arg(&arg_target_env),
+                )
+                .subcommand(
+                    SubCommand::with_name(SUBCMD_EXPORT_SSH_INCLUDE)
+                        .about("Export the machines of an environment as ssh_config Host blocks")
+                        .arg(&arg_target_env),
+                )
+                .subcommand(
+                    SubCommand::with_name(SUBCMD_EXPORT_ANSIBLE_INVENTORY)
+                        .about("Export the machines of an environment as an Ansible inventory")
+                        .arg(&arg_target_env),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_CONFIG)
+                .about("Configuration tools")
+                .subcommand(
+                    SubCommand::with_name(SUBCMD_CONFIG_CHECK)
+                        .visible_alias("validate")
+                        .about("Check the configuration (e.g. names against the naming policy)"),
+                )
+                .subcommand(
+                    SubCommand::with_name(SUBCMD_CONFIG_EDIT)
+                        .about("Open the resources file in $EDITOR, creating it if needed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_QUERY)
+                .about("Print a value of the resources file")
+                .arg(
+                    Arg::with_name(ARG_QUERY_PATH)
+                        .help("Dotted path to the value (e.g. server.prod.web1.ip)")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_TRUST)
+                .about("Trust the host keys of machines, scanned with ssh-keyscan")
+                .arg(&arg_target_env)
+                .arg(
+                    Arg::with_name(ARG_MACHINE)
+                        .help("machine to trust")
+                        .required_unless(ARG_TRUST_ALL),
+                )
+                .arg(
+                    Arg::with_name(ARG_TRUST_ALL)
+                        .help("Trust all the machines, rebuilding the known hosts of the environment")
+                        .long("all")
+                        .conflicts_with(ARG_MACHINE),
+                )
+                .arg(
+                    Arg::with_name(ARG_TRUST_APPEND)
+                        .help("Keep the known hosts of the other hosts instead of rebuilding")
+                        .long("append")
+                        .requires(ARG_TRUST_ALL),
+                )
+                .arg(
+                    Arg::with_name(ARG_TRUST_JOBS)
+                        .help("Number of concurrent scans")
+                        .takes_value(true)
+                        .long("jobs")
+                        .short("j")
+                        .default_value("8"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_KNOWN_HOSTS)
+                .about("Manage the known hosts of the environments")
+                .subcommand(
+                    SubCommand::with_name(SUBCMD_KNOWN_HOSTS_SYNC)
+                        .about("Add the missing host keys of the machines, scanned with ssh-keyscan")
+                        .arg(&arg_target_env)
+                        .arg(
+                            Arg::with_name(ARG_TRUST_JOBS)
+                                .help("Number of concurrent scans")
+                                .takes_value(true)
+                                .long("jobs")
+                                .short("j")
+                                .default_value("8"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(SUBCMD_KNOWN_HOSTS_PATH)
+                        .about("Print the path of the known hosts file of an environment")
+                        .arg(&arg_target_env),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_RESOLVE)
+                .about("Print how a machine is reached (host, user, port, jump)")
+                .arg(&arg_target_env)
+                .arg(&arg_machine)
+                .arg(
+                    Arg::with_name(ARG_RESOLVE_FORMAT)
+                        .help("Output format")
+                        .takes_value(true)
+                        .possible_values(&["text", "dotenv"])
+                        .default_value("text")
+                        .long("format"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_PING)
+                .about("Check that a machine is reachable")
+                .arg(&arg_target_env)
+                .arg(&arg_machine),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_OTP)
+                .about("Print the current one time password of a machine")
+                .arg(&arg_target_env)
+                .arg(&arg_machine),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_DIFF)
+                .about("Compare the machines of two environments")
+                .arg(
+                    Arg::with_name(ARG_DIFF_ENV_A)
+                        .help("First environment (e.g. staging)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(ARG_DIFF_ENV_B)
+                        .help("Second environment (e.g. prod)")
+                        .required(true),
                 ),
         )
         .subcommand(
             SubCommand::with_name(SUBCMD_LIST)
                 .about("List resources")
-                .arg(arg_target_env),
+                .arg(&arg_target_env)
+                .arg(
+                    Arg::with_name(ARG_LIST_OS)
+                        .help("Only list machines declaring this operating system")
+                        .takes_value(true)
+                        .long("os"),
+                )
+                .arg(
+                    Arg::with_name(ARG_LIST_RESOURCES)
+                        .help("List resources with the forwarding done by tunnel")
+                        .long("resources"),
+                )
+                .arg(
+                    Arg::with_name(ARG_LIST_GROUP)
+                        .help("Only list machines of this group")
+                        .takes_value(true)
+                        .long("group"),
+                )
+                .arg(
+                    Arg::with_name(ARG_LIST_FORMAT)
+                        .help("Output format (default to plain)")
+                        .takes_value(true)
+                        .possible_values(&["plain", "json"])
+                        .conflicts_with(ARG_LIST_RESOURCES)
+                        .long("format"),
+                )
+                .arg(
+                    Arg::with_name(ARG_LIST_SUMMARY)
+                        .help("Print the number of machines and proxies of each environment (or of -t)")
+                        .long("summary")
+                        .conflicts_with_all(&[
+                            ARG_LIST_OS,
+                            ARG_LIST_RESOURCES,
+                            ARG_LIST_GROUP,
+                            ARG_LIST_FORMAT,
+                        ]),
+                )
+                .arg(
+                    Arg::with_name(ARG_LIST_PATTERN)
+                        .help("Only list the names matching this glob, ignoring case (e.g. 'prod-*')"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_RESOURCES)
+                .about("List the resources (of all environments, or of one with -t)")
+                .arg(&arg_target_env),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_COMPLETION)
+                .about("Print the completion script of a shell")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::with_name(ARG_COMPLETION_SHELL)
+                        .help("Shell to complete")
+                        .possible_values(&["bash", "zsh", "fish"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SUBCMD_COMPLETE)
+                .about("Print the environments, or machines of an environment, starting with a prefix")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::with_name(ARG_TARGET_ENV)
+                        .help("Environment to complete the machines of")
+                        .takes_value(true)
+                        .short("t")
+                        .long("target"),
+                )
+                .arg(
+                    Arg::with_name(ARG_COMPLETE_PREFIX)
+                        .help("Prefix of the names")
+                        .default_value(""),
+                ),
+        )
+}
+
+fn main() -> Result<()> {
+    let m = build_cli().get_matches();
+
+    // completion doesn't need any resources file
+    if let Some(m) = m.subcommand_matches(SUBCMD_COMPLETION) {
+        let shell: Shell = m
+            .value_of(ARG_COMPLETION_SHELL)
+            .unwrap()
+            .parse()
+            .map_err(|e| anyhow!("{}", e))?;
+        build_cli().gen_completions_to("machlist", shell, &mut std::io::stdout());
+        return Ok(());
+    }
+    if let Some(env_file) = m.value_of(ARG_ENV_FILE) {
+        load_env_file(Path::new(env_file))?;
+    }
+    if let Some(sub) = m.subcommand_matches(SUBCMD_COMPLETE) {
+        complete(
+            m.value_of(ARG_RES_FILE),
+            sub.value_of(ARG_TARGET_ENV),
+            sub.value_of(ARG_COMPLETE_PREFIX).unwrap(),
         );
-    let m = app.get_matches();
+        return Ok(());
+    }
+    // the resources file is edited without being parsed, being possibly invalid or missing
+    if let Some(sub) = m.subcommand_matches(SUBCMD_CONFIG) {
+        if sub.subcommand_matches(SUBCMD_CONFIG_EDIT).is_some() {
+            return config_edit(m.value_of(ARG_RES_FILE));
+        }
+    }
 
     let verbose = m.occurrences_of(ARG_VERBOSE);
-    let res_file = m.value_of(ARG_RES_FILE).unwrap().into();
+    let res_file = resolve_resource_file(m.value_of(ARG_RES_FILE))?;
+
+    let compress = m.is_present(ARG_COMPRESS);
+    let keepalive = m
+        .value_of(ARG_KEEPALIVE)
+        .map(|x| x.parse::<u32>())
+        .transpose()
+        .context("keepalive is not a valid number of seconds")?;
+    let known_hosts_mode = m.value_of(ARG_KNOWN_HOSTS_MODE).map(|x| x.to_string());
+    let no_webhook = m.is_present(ARG_NO_WEBHOOK);
+    let trace = m.value_of(ARG_TRACE).map(PathBuf::from);
+    let log = m
+        .value_of(ARG_LOG)
+        .map(|log| expand_home(&env_value(log)?))
+        .transpose()?
+        .map(PathBuf::from);
+    let netns = m.value_of(ARG_NETNS).map(|x| x.to_string());
+    let identity = m.value_of(ARG_IDENTITY).map(|x| x.to_string());
+    let user = m.value_of(ARG_USER).map(|x| x.to_string());
+    let jump = m.value_of(ARG_JUMP).map(|x| x.to_string());
+    let known_hosts_dir = m.value_of(ARG_KNOWN_HOSTS_DIR).map(|x| x.to_string());
+    let ssh_options = m
+        .values_of(ARG_SSH_OPTION)
+        .map(|values| {
+            values
+                .map(|x| {
+                    if !x.contains('=') {
+                        bail!("invalid ssh option '{}', expecting KEY=VALUE", x)
+                    }
+                    Ok(x.to_string())
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let quiet = m.is_present(ARG_QUIET);
+    let dry_run = m.is_present(ARG_DRY_RUN);
+    let prefer_family = if m.is_present(ARG_PREFER_IPV6) {
+        Some(AddressFamily::Inet6)
+    } else if m.is_present(ARG_PREFER_IPV4) {
+        Some(AddressFamily::Inet)
+    } else {
+        None
+    };
+
+    let common = CommonArgs {
+        verbose,
+        compress,
+        keepalive,
+        known_hosts_mode,
+        no_webhook,
+        trace,
+        log,
+        prefer_family,
+        netns,
+        identity,
+        user,
+        jump,
+        ssh_options,
+        known_hosts_dir,
+        quiet,
+        dry_run,
+        res_file,
+    };
 
-    let common = CommonArgs { verbose, res_file };
+    let interactive_terminal = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    if m.subcommand_name().is_none() && !interactive_terminal {
+        bail!("No command specified");
+    }
 
-    const DEFAULT_ENV: &str = "alpha";
+    // the resources are parsed once, and shared by everything done in this invocation
+    let resources = &parse_resources(&common.res_file)?;
+    let common = common.with_defaults(&resources.defaults);
 
     if let Some(m) = m.subcommand_matches(SUBCMD_SHELL) {
-        let target_env = m.value_of(ARG_TARGET_ENV).unwrap_or(DEFAULT_ENV);
-        let machine = m.value_of(ARG_MACHINE).unwrap();
-        shell(&common, &target_env, &machine)
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let opts = ShellOpts {
+            stop_at: m.value_of(ARG_SHELL_STOP_AT),
+            verify_identity: m.is_present(ARG_SHELL_VERIFY_IDENTITY),
+            record: m.value_of(ARG_SHELL_RECORD).map(Path::new),
+            explain: m.is_present(ARG_EXPLAIN),
+            mosh: m.is_present(ARG_SHELL_MOSH),
+            adhoc: m.value_of(ARG_SHELL_ADHOC),
+        };
+        match opts.adhoc {
+            Some(address) => shell_adhoc(&common, resources, target_env, address, &opts),
+            None => {
+                let machine = m.value_of(ARG_MACHINE).unwrap();
+                shell(&common, resources, target_env, machine, &opts)
+            }
+        }
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_EXEC) {
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let remote_command: Vec<&str> = m.values_of(ARG_EXEC_COMMAND).unwrap().collect();
+        if m.is_present(ARG_EXEC_ALL) {
+            let jobs = m
+                .value_of(ARG_EXEC_JOBS)
+                .unwrap()
+                .parse::<usize>()
+                .context("jobs is not a valid number")?;
+            let os = m.value_of(ARG_EXEC_OS);
+            exec_all(&common, resources, target_env, os, &remote_command, jobs)
+        } else {
+            let machine = m.value_of(ARG_MACHINE).unwrap();
+            exec(&common, resources, target_env, machine, &remote_command)
+        }
     } else if let Some(m) = m.subcommand_matches(SUBCMD_LIST) {
         let target_env = m.value_of(ARG_TARGET_ENV);
-        list(&common, &target_env)
+        let opts = ListOpts {
+            os: m.value_of(ARG_LIST_OS),
+            group: m.value_of(ARG_LIST_GROUP),
+            resources: m.is_present(ARG_LIST_RESOURCES),
+            json: m.value_of(ARG_LIST_FORMAT) == Some("json"),
+            pattern: m.value_of(ARG_LIST_PATTERN),
+            summary: m.is_present(ARG_LIST_SUMMARY),
+        };
+        list(resources, &target_env, &opts)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_RESOURCES) {
+        show_resources(resources, m.value_of(ARG_TARGET_ENV))
     } else if let Some(m) = m.subcommand_matches(SUBCMD_COPY_FROM) {
-        let target_env = m.value_of(ARG_TARGET_ENV).unwrap_or(DEFAULT_ENV);
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
         let machine = m.value_of(ARG_MACHINE).unwrap();
-        let copy_path = m.value_of(ARG_COPY_FROM_PATH).unwrap();
-        copy_from(&common, &target_env, machine, copy_path)
+        let copy_paths: Vec<&str> = m.values_of(ARG_COPY_FROM_PATH).unwrap().collect();
+        let opts = CopyFromOpts {
+            resume: m.is_present(ARG_COPY_RESUME),
+            glob: m.is_present(ARG_COPY_FROM_GLOB),
+            recursive: m.is_present(ARG_COPY_FROM_RECURSIVE),
+            dest: m.value_of(ARG_COPY_FROM_DEST),
+        };
+        copy_from(&common, resources, target_env, machine, &copy_paths, &opts)
     } else if let Some(m) = m.subcommand_matches(SUBCMD_COPY_TO) {
-        let target_env = m.value_of(ARG_TARGET_ENV).unwrap_or(DEFAULT_ENV);
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
         let machine = m.value_of(ARG_MACHINE).unwrap();
         let copy_path = m.value_of(ARG_COPY_TO_PATH).unwrap();
-        copy_to(&common, &target_env, machine, copy_path)
+        let resume = m.is_present(ARG_COPY_RESUME);
+        copy_to(&common, resources, target_env, machine, copy_path, resume)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_COPY_BETWEEN) {
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let src = machine_path(m.value_of(ARG_COPY_BETWEEN_SRC).unwrap())?;
+        let dst = machine_path(m.value_of(ARG_COPY_BETWEEN_DST).unwrap())?;
+        copy_between(&common, resources, target_env, src, dst)
     } else if let Some(m) = m.subcommand_matches(SUBCMD_TUNNEL) {
-        let target_env = m.value_of(ARG_TARGET_ENV).unwrap_or(DEFAULT_ENV);
-        let resource = m.value_of(ARG_TUNNEL_RESOURCE).unwrap();
-        let local_port = m.value_of(ARG_TUNNEL_LOCAL_PORT);
-        tunnel(&common, &target_env, resource, local_port)
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let resource_names: Vec<&str> = match m.value_of(ARG_TUNNEL_GROUP) {
+            Some(group) => resources.resource_group_members(target_env, group)?,
+            None => m.values_of(ARG_TUNNEL_RESOURCE).unwrap().collect(),
+        };
+        let opts = TunnelOpts {
+            local_port: m.value_of(ARG_TUNNEL_LOCAL_PORT),
+            family: match m.value_of(ARG_TUNNEL_FAMILY) {
+                Some("4") => Some(AddressFamily::Inet),
+                Some("6") => Some(AddressFamily::Inet6),
+                _ => None,
+            },
+            watch_config: m.is_present(ARG_TUNNEL_WATCH_CONFIG),
+            check: m.is_present(ARG_TUNNEL_CHECK),
+            hosts_alias: m.is_present(ARG_TUNNEL_HOSTS_ALIAS),
+            reconnect: m.is_present(ARG_TUNNEL_RECONNECT),
+            connect: m.is_present(ARG_TUNNEL_CONNECT),
+            reverse: m.is_present(ARG_TUNNEL_REVERSE),
+            bind_address: m.value_of(ARG_TUNNEL_BIND_ADDRESS),
+            background: m.is_present(ARG_TUNNEL_BACKGROUND),
+            explain: m.is_present(ARG_EXPLAIN),
+        };
+        tunnel(&common, resources, target_env, &resource_names, &opts)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_EXPORT) {
+        if let Some(m) = m.subcommand_matches(SUBCMD_EXPORT_TUNNELS) {
+            let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+            export_tunnels(&common, resources, target_env)
+        } else if let Some(m) = m.subcommand_matches(SUBCMD_EXPORT_SSH_INCLUDE) {
+            let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+            export_ssh_include(&common, resources, target_env)
+        } else if let Some(m) = m.subcommand_matches(SUBCMD_EXPORT_ANSIBLE_INVENTORY) {
+            let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+            export_ansible_inventory(&common, resources, target_env)
+        } else {
+            bail!("No export specified");
+        }
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_CONFIG) {
+        if m.subcommand_matches(SUBCMD_CONFIG_CHECK).is_some() {
+            config_check(resources)
+        } else {
+            bail!("No config command specified");
+        }
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_QUERY) {
+        let path = m.value_of(ARG_QUERY_PATH).unwrap();
+        query(resources, path)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_TRUST) {
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let machine = m.value_of(ARG_MACHINE);
+        let append = m.is_present(ARG_TRUST_APPEND);
+        let jobs = m
+            .value_of(ARG_TRUST_JOBS)
+            .unwrap()
+            .parse::<usize>()
+            .context("jobs is not a valid number")?;
+        trust(&common, resources, target_env, machine, append, jobs)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_KNOWN_HOSTS) {
+        if let Some(m) = m.subcommand_matches(SUBCMD_KNOWN_HOSTS_SYNC) {
+            let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+            let jobs = m
+                .value_of(ARG_TRUST_JOBS)
+                .unwrap()
+                .parse::<usize>()
+                .context("jobs is not a valid number")?;
+            known_hosts_sync(&common, resources, target_env, jobs)
+        } else if let Some(m) = m.subcommand_matches(SUBCMD_KNOWN_HOSTS_PATH) {
+            let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+            resources.get_target_env(target_env)?;
+            let path = known_hosts_file(&common, resources, target_env)?;
+            println!("{}", std::env::current_dir()?.join(path).display());
+            Ok(())
+        } else {
+            bail!("No known-hosts command specified");
+        }
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_RESOLVE) {
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let machine = m.value_of(ARG_MACHINE).unwrap();
+        let format = m.value_of(ARG_RESOLVE_FORMAT).unwrap();
+        resolve(&common, resources, target_env, machine, format)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_PING) {
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let machine = m.value_of(ARG_MACHINE).unwrap();
+        ping(&common, resources, target_env, machine)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_OTP) {
+        let target_env = resources.target_env(m.value_of(ARG_TARGET_ENV))?;
+        let machine = m.value_of(ARG_MACHINE).unwrap();
+        otp(resources, target_env, machine)
+    } else if let Some(m) = m.subcommand_matches(SUBCMD_DIFF) {
+        let env_a = m.value_of(ARG_DIFF_ENV_A).unwrap();
+        let env_b = m.value_of(ARG_DIFF_ENV_B).unwrap();
+        diff(resources, env_a, env_b)
     } else if let Some(name) = m.subcommand_name() {
         bail!("Unknown command {}", name);
     } else {
-        bail!("No command specified");
+        interactive(&common, resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resources(config: &str) -> Resource {
+        toml::from_str(config).unwrap()
+    }
+
+    /// Whether the arguments contain the given ones, in a row
+    fn has_args(args: &[String], expected: &[&str]) -> bool {
+        args.windows(expected.len()).any(|w| w == expected)
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ssh_login_port() {
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            port = 2222
+            [server.prod.db]
+            ip = "10.0.0.2"
+            [resource]
+            "#,
+        );
+        let common = CommonArgs::default();
+        let ssh_opt = ssh_login(&common, &resources, "prod", "web").unwrap();
+        assert!(has_args(&ssh_opt.args, &["-p", "2222"]));
+        assert_eq!(ssh_opt.dest, "10.0.0.1");
+
+        let ssh_opt = ssh_login(&common, &resources, "prod", "db").unwrap();
+        assert!(!ssh_opt.args.iter().any(|arg| arg == "-p"));
+    }
+
+    #[test]
+    fn copy_from_quotes_remote_path() {
+        let ssh_opt = Ssh {
+            args: Vec::new(),
+            dest: "bob@10.0.0.2".to_string(),
+            trace: Vec::new(),
+        };
+        assert_eq!(
+            copy_from_path(&ssh_opt, "/var/log/*.log", false),
+            "bob@10.0.0.2:'/var/log/*.log'"
+        );
+        assert_eq!(
+            copy_from_path(&ssh_opt, "/tmp/a b", false),
+            "bob@10.0.0.2:'/tmp/a b'"
+        );
+        assert_eq!(
+            copy_from_path(&ssh_opt, "/var/log/*.log", true),
+            "bob@10.0.0.2:/var/log/*.log"
+        );
+    }
+
+    #[test]
+    fn rsync_transport_quotes_arguments() {
+        let common = CommonArgs::default();
+        let ssh_args = vec!["-oProxyCommand=aws ssm start-session --target %h".to_string()];
+        let command = copy_command(&common, &ssh_args, true);
+        let args = args(&command);
+        assert_eq!(
+            args[3],
+            "ssh '-oProxyCommand=aws ssm start-session --target %h'"
+        );
+    }
+
+    #[test]
+    fn list_json_round_trip() {
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            [server.prod."db \"main\""]
+            ip = "10.0.0.2"
+            [server.prod.jump]
+            ip = "10.0.0.3"
+            proxy = true
+            [resource]
+            "#,
+        );
+        let mut machines: Vec<(&String, &ServerDef)> =
+            resources.server["prod"].list_non_proxies().collect();
+        machines.sort_by(|a, b| a.0.cmp(b.0));
+        let json = machines_json(&machines).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let names: Vec<&str> = parsed
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|machine| machine["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["db \"main\"", "web"]);
+        assert_eq!(parsed[1]["ip"], "10.0.0.1");
+        assert_eq!(parsed[1]["proxy"], false);
+
+        assert_eq!(machines_json(&[]).unwrap(), "[]");
+    }
+
+    #[test]
+    fn env_addresses_are_resolved() {
+        std::env::set_var("MACHLIST_TEST_WEB_IP", "10.0.0.7");
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "env:MACHLIST_TEST_WEB_IP"
+            [server.prod.db]
+            name = "env:MACHLIST_TEST_WEB_IP"
+            [server.prod.cache]
+            ip = "env:MACHLIST_TEST_MISSING_IP"
+            [resource]
+            "#,
+        );
+        let envdef = &resources.server["prod"];
+        let host = |name: &str| envdef.get_machine(name).unwrap().host(None);
+        assert_eq!(host("web").unwrap().as_deref(), Some("10.0.0.7"));
+        assert_eq!(host("db").unwrap().as_deref(), Some("10.0.0.7"));
+        assert!(host("cache").is_err());
+
+        let common = CommonArgs::default();
+        let command = keyscan_command(&common, &resources, "prod", "web").unwrap();
+        assert_eq!(args(&command).last().unwrap(), "10.0.0.7");
+    }
+
+    #[test]
+    fn ssh_login_identity() {
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            [server.prod.db]
+            ip = "10.0.0.2"
+            identity = "/keys/db"
+            [resource]
+            "#,
+        );
+        let common = CommonArgs {
+            identity: Some("/keys/default".to_string()),
+            ..CommonArgs::default()
+        };
+        let ssh_opt = ssh_login(&common, &resources, "prod", "web").unwrap();
+        assert!(has_args(&ssh_opt.args, &["-i", "/keys/default"]));
+
+        let ssh_opt = ssh_login(&common, &resources, "prod", "db").unwrap();
+        assert!(has_args(&ssh_opt.args, &["-i", "/keys/db"]));
+        assert!(!ssh_opt.args.iter().any(|arg| arg == "/keys/default"));
+    }
+
+    #[test]
+    fn ssh_options_after_subcommand() {
+        let m = build_cli().get_matches_from(vec![
+            "machlist", "shell", "-t", "prod", "-o", "Foo=bar", "-o", "Baz=qux", "web",
+        ]);
+        let ssh_options: Vec<String> = m
+            .values_of(ARG_SSH_OPTION)
+            .unwrap()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(ssh_options, vec!["Foo=bar", "Baz=qux"]);
+
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            [resource]
+            "#,
+        );
+        let common = CommonArgs {
+            ssh_options,
+            ..CommonArgs::default()
+        };
+        let ssh_opt = ssh_login(&common, &resources, "prod", "web").unwrap();
+        let options: Vec<&String> = ssh_opt
+            .args
+            .iter()
+            .filter(|arg| arg.as_str() == "-oFoo=bar" || arg.as_str() == "-oBaz=qux")
+            .collect();
+        assert_eq!(options, vec!["-oFoo=bar", "-oBaz=qux"]);
+    }
+
+    #[test]
+    fn ssh_defaults_args() {
+        let common = CommonArgs::default();
+        assert_eq!(
+            SshDefaults::default().args(&common),
+            vec![
+                "-oConnectTimeout=10",
+                "-oServerAliveInterval=30",
+                "-oServerAliveCountMax=3",
+            ]
+        );
+
+        let defaults = SshDefaults {
+            connect_timeout: Some(5),
+            server_alive_interval: Some(0),
+            server_alive_count_max: None,
+        };
+        assert_eq!(
+            defaults.args(&common),
+            vec!["-oConnectTimeout=5", "-oServerAliveCountMax=3"]
+        );
+    }
+
+    #[test]
+    fn ssh_defaults_overridden_by_options() {
+        let common = CommonArgs {
+            ssh_options: vec!["connecttimeout=3".to_string()],
+            keepalive: Some(60),
+            ..CommonArgs::default()
+        };
+        assert_eq!(
+            SshDefaults::default().args(&common),
+            vec!["-oServerAliveCountMax=3"]
+        );
+    }
+
+    #[test]
+    fn ssh_login_user_override() {
+        let resources = resources(
+            r#"
+            username = "bob"
+            [server.prod.jump]
+            ip = "10.0.0.9"
+            proxy = true
+            [server.prod.web]
+            ip = "10.0.0.1"
+            jump = "jump"
+            username = "alice"
+            [resource]
+            "#,
+        );
+        let common = CommonArgs {
+            user: Some("root".to_string()),
+            ..CommonArgs::default()
+        };
+        let ssh_opt = ssh_login(&common, &resources, "prod", "web").unwrap();
+        assert_eq!(ssh_opt.dest, "root@10.0.0.1");
+        assert!(has_args(&ssh_opt.args, &["-J", "root@10.0.0.9"]));
+    }
+
+    #[test]
+    fn username_precedence() {
+        std::env::set_var("MACHLIST_TEST_STAGING_USER", "deploy");
+        let resources = resources(
+            r#"
+            username = "bob"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            username = "alice"
+            [server.prod.db]
+            ip = "10.0.0.2"
+            [server.staging.web]
+            ip = "10.0.1.1"
+            [environment.prod]
+            username = "carol"
+            [environment.staging]
+            username = "env:MACHLIST_TEST_STAGING_USER"
+            [resource]
+            "#,
+        );
+        let username =
+            |env: &str, machine: &str| resources.get_username(None, env, machine).unwrap().unwrap();
+        assert_eq!(username("prod", "web"), "alice");
+        assert_eq!(username("prod", "db"), "carol");
+        assert_eq!(username("staging", "web"), "deploy");
+
+        let resources = Resource {
+            environment: HashMap::new(),
+            ..resources
+        };
+        assert_eq!(
+            resources
+                .get_username(None, "prod", "db")
+                .unwrap()
+                .as_deref(),
+            Some("bob")
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let parse = |config: &str| toml::from_str::<Resource>(config);
+        let e = parse(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            prxoy = true
+            [resource]
+            "#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("prxoy"));
+
+        let e = parse(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            [resource.prod.db]
+            server = "web"
+            prot = 5432
+            "#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("prot"));
+
+        assert!(parse("usrname = \"bob\"\n[server]\n[resource]\n").is_err());
+    }
+
+    #[test]
+    fn bracket_ipv6_addresses() {
+        assert_eq!(bracket_ipv6("10.0.0.1"), "10.0.0.1");
+        assert_eq!(bracket_ipv6("db.internal"), "db.internal");
+        assert_eq!(bracket_ipv6("fe80::1"), "[fe80::1]");
+        assert_eq!(bracket_ipv6("[fe80::1]"), "[fe80::1]");
+        assert_eq!(unbracket("[fe80::1]"), "fe80::1");
+    }
+
+    #[test]
+    fn forwarding_ipv4_and_ipv6() {
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            [resource.prod.v4]
+            server = "web"
+            at = "10.0.0.5"
+            port = 5432
+            [resource.prod.v6]
+            server = "web"
+            at = "fe80::5"
+            port = 5432
+            [resource.prod.bracketed]
+            server = "web"
+            at = "[fe80::5]"
+            port = 5432
+            "#,
+        );
+        let forwarding = |name: &str| {
+            resources.resource["prod"].0[name]
+                .forwarding(15432)
+                .unwrap()
+        };
+        assert_eq!(forwarding("v4"), "15432:10.0.0.5:5432");
+        assert_eq!(forwarding("v6"), "15432:[fe80::5]:5432");
+        assert_eq!(forwarding("bracketed"), "15432:[fe80::5]:5432");
+    }
+
+    #[test]
+    fn jump_hosts_ipv4_and_ipv6() {
+        let resources = resources(
+            r#"
+            username = "bob"
+            [server.prod.jump4]
+            ip = "10.0.0.9"
+            proxy = true
+            [server.prod.jump6]
+            ip = "fe80::9"
+            port = 2222
+            proxy = true
+            [server.prod.web]
+            ip = "fe80::1"
+            jump = ["jump4", "jump6"]
+            [resource]
+            "#,
+        );
+        let common = CommonArgs::default();
+        let ssh_opt = ssh_login(&common, &resources, "prod", "web").unwrap();
+        assert!(has_args(
+            &ssh_opt.args,
+            &["-J", "bob@10.0.0.9,bob@[fe80::9]:2222"]
+        ));
+        assert_eq!(ssh_opt.dest, "bob@fe80::1");
+    }
+
+    #[test]
+    fn config_check_integrity() {
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            jump = "bastion"
+            [server.prod.ssm]
+            ip = "10.0.0.2"
+            jump = "web"
+            proxy_command = "aws ssm start-session --target %h"
+            [resource.prod.db]
+            server = "cache"
+            port = 0
+            [resource_group.prod]
+            data = ["db", "redis"]
+            "#,
+        );
+        assert_eq!(
+            check_integrity(&resources),
+            vec![
+                "server.prod.ssm: jump and proxy_command can't both be set",
+                "server.prod.web: jump `bastion` doesn't exist",
+                "resource.prod.db: server `cache` doesn't exist",
+                "resource.prod.db: port 0 can't be tunneled",
+                "resource_group.prod.data: resource `redis` doesn't exist",
+            ]
+        );
+        assert!(config_check(&resources).is_err());
+    }
+
+    #[test]
+    fn config_check_references() {
+        let resources = resources(
+            r#"
+            [server.prod.bastion]
+            ip = "10.0.0.9"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            jump = "bastion"
+            [resource.prod.db]
+            server = "web"
+            port = 5432
+            [resource.prod.replica]
+            server = "web"
+            port = 5432
+            "#,
+        );
+        assert!(check_integrity(&resources).is_empty());
+        assert_eq!(
+            check_references(&resources),
+            vec![
+                "resource.prod: resources `db`, `replica` are tunneled on the same local port 5432, set a distinct `local_port` to open them together",
+                "server.prod: machine `bastion` is used as a jump but isn't marked `proxy = true`, so it shows up in list",
+            ]
+        );
+    }
+
+    #[test]
+    fn config_check_naming() {
+        let resources = resources(
+            r#"
+            [server.prod.web-1]
+            ip = "10.0.0.1"
+            [server.prod.Web_2]
+            ip = "10.0.0.2"
+            [resource.prod.db]
+            server = "web-1"
+            port = 5432
+            "#,
+        );
+        let policy = Pattern::new("^[a-z]+(-[a-z0-9]+)*$").unwrap();
+        assert_eq!(
+            check_naming(&resources, &policy),
+            vec!["server.prod: machine name `Web_2` doesn't match ^[a-z]+(-[a-z0-9]+)*$"]
+        );
+    }
+
+    #[test]
+    fn expand_home_paths() {
+        let home = home().unwrap().display().to_string();
+        assert_eq!(expand_home("~").unwrap(), home);
+        assert_eq!(expand_home("~/x").unwrap(), format!("{}/x", home));
+        assert_eq!(expand_home("~user/x").unwrap(), "~user/x");
+        assert_eq!(expand_home("/tmp/~x").unwrap(), "/tmp/~x");
+        assert_eq!(expand_home("x").unwrap(), "x");
+    }
+
+    #[test]
+    fn compression() {
+        let resources = resources(
+            r#"
+            [server.prod.far]
+            ip = "10.0.0.1"
+            compress = true
+            [server.prod.near]
+            ip = "10.0.0.2"
+            [resource]
+            "#,
+        );
+        let common = CommonArgs::default();
+        assert!(common.transport_args().is_empty());
+        let ssh_opt = ssh_login(&common, &resources, "prod", "far").unwrap();
+        assert!(ssh_opt.args.iter().any(|arg| arg == "-C"));
+        let ssh_opt = ssh_login(&common, &resources, "prod", "near").unwrap();
+        assert!(!ssh_opt.args.iter().any(|arg| arg == "-C"));
+
+        // with -C, it's given once to ssh, with the transport arguments
+        let common = CommonArgs {
+            compress: true,
+            ..CommonArgs::default()
+        };
+        assert_eq!(common.transport_args(), vec!["-C"]);
+        let ssh_opt = ssh_login(&common, &resources, "prod", "far").unwrap();
+        assert!(!ssh_opt.args.iter().any(|arg| arg == "-C"));
+    }
+
+    #[test]
+    fn tunnel_resources_full_names() {
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            [resource.prod.postgres]
+            server = "web"
+            port = 5432
+            [resource.prod.redis]
+            server = "web"
+            port = 6379
+            "#,
+        );
+        let names = |given: &[&str]| -> Vec<&str> {
+            tunnel_resources(&resources, "prod", given)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        assert_eq!(names(&["postgres"]), vec!["postgres"]);
+        assert_eq!(names(&["post", "dis"]), vec!["postgres", "redis"]);
+    }
+
+    #[test]
+    fn ssh_config_host_options() {
+        let ssh_opt = Ssh {
+            args: vec![
+                "-oUserKnownHostsFile=/tmp/known_hosts".to_string(),
+                "-F".to_string(),
+                "/etc/ssh/prod.conf".to_string(),
+                "-i".to_string(),
+                "/keys/web".to_string(),
+                "-C".to_string(),
+                "-A".to_string(),
+                "-J".to_string(),
+                "bob@10.0.0.9".to_string(),
+                "-p".to_string(),
+                "2222".to_string(),
+            ],
+            dest: "bob@10.0.0.1".to_string(),
+            trace: Vec::new(),
+        };
+        let common = CommonArgs::default();
+        assert_eq!(
+            ssh_config_host(&common, "prod.web", &ssh_opt).unwrap(),
+            [
+                "Host prod.web",
+                "    HostName 10.0.0.1",
+                "    User bob",
+                "    UserKnownHostsFile /tmp/known_hosts",
+                "    IdentityFile /keys/web",
+                "    Compression yes",
+                "    ForwardAgent yes",
+                "    ProxyJump bob@10.0.0.9",
+                "    Port 2222",
+                "    Include /etc/ssh/prod.conf",
+            ]
+            .join("\n")
+        );
+
+        let ssh_opt = Ssh {
+            args: vec!["-x".to_string()],
+            dest: "10.0.0.1".to_string(),
+            trace: Vec::new(),
+        };
+        assert!(ssh_config_host(&common, "prod.web", &ssh_opt).is_err());
+    }
+
+    #[test]
+    fn ssh_config_host_compress_flag() {
+        let ssh_opt = Ssh {
+            args: Vec::new(),
+            dest: "10.0.0.1".to_string(),
+            trace: Vec::new(),
+        };
+        let common = CommonArgs {
+            compress: true,
+            ..CommonArgs::default()
+        };
+        assert_eq!(
+            ssh_config_host(&common, "prod.web", &ssh_opt).unwrap(),
+            "Host prod.web\n    HostName 10.0.0.1\n    Compression yes"
+        );
+    }
+
+    #[test]
+    fn copy_between_different_options() {
+        let resources = resources(
+            r#"
+            username = "bob"
+            [server.prod.jump]
+            ip = "10.0.0.9"
+            proxy = true
+            [server.prod.web]
+            ip = "10.0.0.1"
+            port = 2222
+            identity = "/keys/web"
+            [server.prod.db]
+            ip = "10.0.0.2"
+            jump = "jump"
+            [resource]
+            "#,
+        );
+        let common = CommonArgs {
+            known_hosts_dir: Some("/tmp".to_string()),
+            ..CommonArgs::default()
+        };
+        let src_opt = ssh_login(&common, &resources, "prod", "web").unwrap();
+        let dst_opt = ssh_login(&common, &resources, "prod", "db").unwrap();
+        let config = copy_between_config(&common, &src_opt, &dst_opt).unwrap();
+        let (src, dst) = config.split_once("\n\n").unwrap();
+        assert!(src.starts_with("Host machlist-src\n    HostName 10.0.0.1\n    User bob\n"));
+        assert!(src.contains("\n    Port 2222\n"));
+        assert!(src.contains("\n    IdentityFile /keys/web\n"));
+        assert!(src.ends_with("\n    Include ~/.ssh/config"));
+        assert!(dst.starts_with("Host machlist-dst\n    HostName 10.0.0.2\n    User bob\n"));
+        assert!(dst.contains("\n    ProxyJump bob@10.0.0.9\n"));
+        assert!(!dst.contains("Port"));
+        assert!(!dst.contains("IdentityFile"));
+    }
+
+    #[test]
+    fn find_rules() {
+        let resources = resources(
+            r#"
+            [server.prod.web]
+            ip = "10.0.0.1"
+            [server.prod.web-1]
+            ip = "10.0.0.2"
+            [server.prod.db-main]
+            ip = "10.0.0.3"
+            [server.prod.cache-main]
+            ip = "10.0.0.4"
+            [server.prod.maintenance]
+            ip = "10.0.0.5"
+            [resource]
+            "#,
+        );
+        let envdef = &resources.server["prod"];
+        let find = |name: &str| {
+            envdef
+                .find(name)
+                .unwrap()
+                .map(|(name, _, rule)| (name.as_str(), rule))
+        };
+        // exact beats prefix, web being a prefix of web-1
+        assert_eq!(find("web"), Some(("web", NameMatch::Exact)));
+        assert_eq!(find("web-"), Some(("web-1", NameMatch::Prefix)));
+        // prefix beats substring, main being in db-main and cache-main
+        assert_eq!(find("main"), Some(("maintenance", NameMatch::Prefix)));
+        assert_eq!(find("-1"), Some(("web-1", NameMatch::Substring)));
+        assert_eq!(find("nope"), None);
+
+        match envdef.find("-main") {
+            Err(MachlistError::Ambiguous { name, candidates }) => {
+                assert_eq!(name, "-main");
+                assert_eq!(candidates, vec!["cache-main", "db-main"]);
+            }
+            _ => panic!("-main should be ambiguous"),
+        }
+
+        let common = CommonArgs::default();
+        let ssh_opt = ssh_login(&common, &resources, "prod", "-1").unwrap();
+        assert_eq!(
+            ssh_opt.trace[0],
+            "machine web-1 found in environment prod by substring -1"
+        );
+    }
+
+    #[test]
+    fn exec_machines_os_filter() {
+        let resources = resources(
+            r#"
+            [server.prod.bastion]
+            ip = "10.0.0.9"
+            os = "linux"
+            proxy = true
+            [server.prod.web]
+            ip = "10.0.0.1"
+            os = "Linux"
+            [server.prod.db]
+            ip = "10.0.0.2"
+            os = "linux"
+            [server.prod.mac]
+            ip = "10.0.0.3"
+            os = "macos"
+            [server.prod.unknown]
+            ip = "10.0.0.4"
+            [resource]
+            "#,
+        );
+        assert_eq!(
+            exec_machines(&resources, "prod", None).unwrap(),
+            vec!["db", "mac", "unknown", "web"]
+        );
+        assert_eq!(
+            exec_machines(&resources, "prod", Some("linux")).unwrap(),
+            vec!["db", "web"]
+        );
+        assert!(exec_machines(&resources, "prod", Some("freebsd")).is_err());
+
+        let m = build_cli().get_matches_from(vec![
+            "machlist", "exec", "-t", "prod", "--all", "--os", "linux", "--", "uptime",
+        ]);
+        let m = m.subcommand_matches(SUBCMD_EXEC).unwrap();
+        assert!(m.is_present(ARG_EXEC_ALL));
+        assert_eq!(m.value_of(ARG_EXEC_OS), Some("linux"));
+        assert!(build_cli()
+            .get_matches_from_safe(vec!["machlist", "exec", "-t", "prod", "--", "uptime"])
+            .is_err());
     }
 }
@@ -0,0 +1,141 @@
+//! Patterns names are checked against: the regular expression of the naming
+//! policy, and the shell globs (`prod-*`) filtering listings
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    /// Regular expression, matching anywhere in a name unless anchored with `^`/`$`
+    pub fn new(source: &str) -> Result<Self> {
+        let regex = Regex::new(source).with_context(|| format!("invalid pattern {}", source))?;
+        Ok(Pattern {
+            source: source.to_string(),
+            regex,
+        })
+    }
+
+    /// Shell glob matching whole names: `*`, `?` and classes (`[a-c]`, `[!_]`)
+    pub fn glob(glob: &str) -> Result<Self> {
+        let mut regex = String::from("^");
+        let mut in_class = false;
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, in_class) {
+                ('*', false) => regex.push_str(".*"),
+                ('?', false) => regex.push('.'),
+                ('[', false) => {
+                    in_class = true;
+                    regex.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        regex.push('^');
+                    }
+                }
+                (']', true) => {
+                    in_class = false;
+                    regex.push(']');
+                }
+                // ranges are kept, other characters of a class being escaped
+                // as the regex classes have their own operators (`&&`, `~~`)
+                (c, true) if c.is_alphanumeric() || c == '-' => regex.push(c),
+                (c, true) => {
+                    regex.push('\\');
+                    regex.push(c);
+                }
+                (c, false) => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        let regex = Regex::new(&regex).with_context(|| format!("invalid glob {}", glob))?;
+        Ok(Pattern {
+            source: glob.to_string(),
+            regex,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.regex.is_match(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regex_with_groups_and_alternations() {
+        let policy = Pattern::new("^[a-z]+(-[a-z0-9]+)*$").unwrap();
+        assert!(policy.is_match("prod"));
+        assert!(policy.is_match("web-1"));
+        assert!(!policy.is_match("Web-1"));
+        assert!(!policy.is_match("web_1"));
+
+        let policy = Pattern::new("^(web|db)[0-9]{2}$").unwrap();
+        assert!(policy.is_match("web01"));
+        assert!(policy.is_match("db42"));
+        assert!(!policy.is_match("web1"));
+        assert!(!policy.is_match("cache01"));
+    }
+
+    #[test]
+    fn regex_unanchored_matches_anywhere() {
+        let pattern = Pattern::new("db").unwrap();
+        assert!(pattern.is_match("main-db-1"));
+        assert!(!pattern.is_match("web"));
+    }
+
+    #[test]
+    fn regex_invalid() {
+        assert!(Pattern::new("^[a-z").is_err());
+        assert!(Pattern::new("(web").is_err());
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        let glob = Pattern::glob("prod-*").unwrap();
+        assert!(glob.is_match("prod-eu"));
+        assert!(glob.is_match("prod-"));
+        assert!(!glob.is_match("preprod-eu"));
+        assert_eq!(glob.as_str(), "prod-*");
+
+        let glob = Pattern::glob("w?b").unwrap();
+        assert!(glob.is_match("web"));
+        assert!(!glob.is_match("webs"));
+    }
+
+    #[test]
+    fn glob_literals_are_escaped() {
+        let glob = Pattern::glob("db.1+").unwrap();
+        assert!(glob.is_match("db.1+"));
+        assert!(!glob.is_match("dbx1+"));
+        assert!(!glob.is_match("db.11"));
+    }
+
+    #[test]
+    fn glob_classes() {
+        let glob = Pattern::glob("web[12]").unwrap();
+        assert!(glob.is_match("web1"));
+        assert!(glob.is_match("web2"));
+        assert!(!glob.is_match("web3"));
+
+        let glob = Pattern::glob("web[!1]").unwrap();
+        assert!(glob.is_match("web2"));
+        assert!(!glob.is_match("web1"));
+
+        let glob = Pattern::glob("node[a-c]").unwrap();
+        assert!(glob.is_match("nodeb"));
+        assert!(!glob.is_match("noded"));
+
+        assert!(Pattern::glob("x[").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! Boolean expressions over machine tags, used to define groups
+//!
+//! e.g. `web && !canary`, `(db || cache) && prod`
+
+use anyhow::{anyhow, bail, Result};
+
+#[derive(Clone, Debug)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Tag(String),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => (),
+            '!' => tokens.push(Token::Not),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '&' | '|' => {
+                if chars.next() != Some(c) {
+                    bail!("expecting {}{} in {}", c, c, s)
+                }
+                tokens.push(if c == '&' { Token::And } else { Token::Or })
+            }
+            c if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' => {
+                let mut tag = c.to_string();
+                while let Some(c) = chars.peek() {
+                    if c.is_alphanumeric() || *c == '-' || *c == '_' || *c == '.' {
+                        tag.push(*c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Tag(tag))
+            }
+            c => bail!("unexpected character {} in {}", c, s),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // or := and ( '||' and )*
+    fn or(&mut self) -> Result<TagExpr> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    // and := not ( '&&' not )*
+    fn and(&mut self) -> Result<TagExpr> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = TagExpr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    // not := '!' not | '(' or ')' | tag
+    fn not(&mut self) -> Result<TagExpr> {
+        match self.next() {
+            Some(Token::Not) => Ok(TagExpr::Not(Box::new(self.not()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => bail!("missing closing parenthesis in {}", self.source),
+                }
+            }
+            Some(Token::Tag(tag)) => Ok(TagExpr::Tag(tag)),
+            _ => Err(anyhow!("expecting a tag in {}", self.source)),
+        }
+    }
+}
+
+impl TagExpr {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            source: s,
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if parser.peek().is_some() {
+            bail!("unexpected trailing input in {}", s)
+        }
+        Ok(expr)
+    }
+
+    /// Tags referenced by the expression
+    pub fn tags(&self) -> Vec<&str> {
+        match self {
+            TagExpr::Tag(tag) => vec![tag.as_str()],
+            TagExpr::Not(e) => e.tags(),
+            TagExpr::And(a, b) | TagExpr::Or(a, b) => {
+                let mut tags = a.tags();
+                tags.extend(b.tags());
+                tags
+            }
+        }
+    }
+
+    pub fn eval(&self, tags: &[String]) -> bool {
+        match self {
+            TagExpr::Tag(tag) => tags.iter().any(|t| t == tag),
+            TagExpr::Not(e) => !e.eval(tags),
+            TagExpr::And(a, b) => a.eval(tags) && b.eval(tags),
+            TagExpr::Or(a, b) => a.eval(tags) || b.eval(tags),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str, tags: &[&str]) -> bool {
+        let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+        TagExpr::parse(expr).unwrap().eval(&tags)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        // a || (b && c)
+        assert!(eval("a || b && c", &["a"]));
+        assert!(!eval("a || b && c", &["b"]));
+        assert!(eval("a || b && c", &["b", "c"]));
+        // (a && b) || c
+        assert!(eval("a && b || c", &["c"]));
+        assert!(!eval("a && b || c", &["a"]));
+    }
+
+    #[test]
+    fn not_binds_to_the_next_operand() {
+        // (!a) && b
+        assert!(eval("!a && b", &["b"]));
+        assert!(!eval("!a && b", &["a", "b"]));
+        assert!(!eval("!a && b", &[]));
+        assert!(eval("!!a", &["a"]));
+        assert!(eval("!(a && b)", &["a"]));
+        assert!(!eval("!(a && b)", &["a", "b"]));
+    }
+
+    #[test]
+    fn parentheses() {
+        assert!(eval("(db || cache) && prod", &["cache", "prod"]));
+        assert!(!eval("(db || cache) && prod", &["db"]));
+        assert!(!eval("(db || cache) && prod", &["prod"]));
+        assert!(eval("((web))", &["web"]));
+    }
+
+    #[test]
+    fn unknown_tags_are_absent() {
+        assert!(!eval("unknown", &["web"]));
+        assert!(eval("!unknown", &["web"]));
+        assert!(eval("web-1.eu_west", &["web-1.eu_west"]));
+        assert_eq!(
+            TagExpr::parse("web && !(db || x)").unwrap().tags(),
+            vec!["web", "db", "x"]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for expr in [
+            "",
+            "   ",
+            "(web",
+            "web)",
+            "(web && db",
+            "web &&",
+            "web ||",
+            "web !",
+            "!",
+            "web & db",
+            "web | db",
+            "web db",
+            "web && $db",
+            "()",
+        ] {
+            assert!(TagExpr::parse(expr).is_err(), "{} should not parse", expr);
+        }
+    }
+}